@@ -0,0 +1,513 @@
+//! Files attached to chat messages
+//!
+//! Attachments are turned into message content parts: images become inline
+//! `image_url` data, text files are inlined under a filename header and PDFs
+//! contribute their extracted text. A copy is kept in the attachments folder
+//! once the message is stored, and removed again with the message.
+
+use crate::db::run_blocking;
+use crate::paths::get_attachments_dir;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use flate2::read::ZlibDecoder;
+use log::warn;
+use serde_json::{json, Value};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Largest image sent inline
+const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Largest text file or PDF read
+const MAX_DOCUMENT_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Most characters of a document inlined into the prompt
+const MAX_INLINE_CHARS: usize = 100_000;
+
+/// Stream dictionary entries marking data that is not page content: images,
+/// embedded font programs, object streams and cross-reference streams
+const NON_CONTENT_STREAM_KEYS: &[&[u8]] = &[
+    b"/Image",
+    b"/FontFile",
+    b"/Length1",
+    b"/Length2",
+    b"/Type1C",
+    b"/CIDFontType0C",
+    b"/OpenType",
+    b"/ObjStm",
+    b"/XRef",
+    b"/Metadata",
+];
+
+/// Image formats that vision models accept inline
+const INLINE_IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// How an attachment is sent to the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentKind {
+    Image,
+    Text,
+    Pdf,
+}
+
+impl AttachmentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttachmentKind::Image => "image",
+            AttachmentKind::Text => "text",
+            AttachmentKind::Pdf => "pdf",
+        }
+    }
+}
+
+/// An attachment read and converted, ready to send
+#[derive(Debug, Clone)]
+pub struct PreparedAttachment {
+    pub kind: AttachmentKind,
+    pub file_name: String,
+    pub mime_type: String,
+    /// The file the user attached
+    pub source_path: PathBuf,
+    pub size_bytes: u64,
+    /// Content part for the chat request
+    pub part: Value,
+}
+
+/// Reads and converts every attachment; fails on the first unusable file.
+/// Reading, encoding and PDF inflation run on the blocking thread pool.
+pub async fn prepare_attachments(paths: Vec<String>) -> Result<Vec<PreparedAttachment>, String> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    run_blocking(move || {
+        paths
+            .iter()
+            .map(|path| prepare_attachment(Path::new(path)))
+            .collect()
+    })
+    .await
+}
+
+fn prepare_attachment(path: &Path) -> Result<PreparedAttachment, String> {
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("Invalid attachment path: {}", path.display()))?
+        .to_string();
+
+    let size_bytes = std::fs::metadata(path)
+        .map_err(|e| format!("Failed to read {}: {}", file_name, e))?
+        .len();
+
+    let mime_type = mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string();
+    let kind = if INLINE_IMAGE_TYPES.contains(&mime_type.as_str()) {
+        AttachmentKind::Image
+    } else if mime_type == "application/pdf" {
+        AttachmentKind::Pdf
+    } else {
+        AttachmentKind::Text
+    };
+
+    let max_bytes = match kind {
+        AttachmentKind::Image => MAX_IMAGE_BYTES,
+        _ => MAX_DOCUMENT_BYTES,
+    };
+    if size_bytes > max_bytes {
+        return Err(format!(
+            "{} is too large to attach ({} MB max)",
+            file_name,
+            max_bytes / (1024 * 1024)
+        ));
+    }
+
+    let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", file_name, e))?;
+
+    let part = match kind {
+        AttachmentKind::Image => json!({
+            "type": "image_url",
+            "image_url": { "url": format!("data:{};base64,{}", mime_type, BASE64.encode(&bytes)) }
+        }),
+        AttachmentKind::Text => {
+            let text = std::str::from_utf8(&bytes)
+                .map_err(|_| format!("{} is not a text file, image or PDF", file_name))?;
+            document_part(&file_name, text)
+        }
+        AttachmentKind::Pdf => {
+            let text = extract_pdf_text(&bytes);
+            if text.trim().is_empty() {
+                document_part(&file_name, "(no extractable text)")
+            } else {
+                document_part(&file_name, text.trim())
+            }
+        }
+    };
+
+    Ok(PreparedAttachment {
+        kind,
+        file_name,
+        mime_type,
+        source_path: path.to_path_buf(),
+        size_bytes,
+        part,
+    })
+}
+
+/// Builds a text part with a filename header, truncating very long documents
+fn document_part(file_name: &str, text: &str) -> Value {
+    let body = match text.char_indices().nth(MAX_INLINE_CHARS) {
+        Some((cut, _)) => format!("{}\n[... truncated]", &text[..cut]),
+        None => text.to_string(),
+    };
+    json!({
+        "type": "text",
+        "text": format!("--- File: {} ---\n{}\n--- End of {} ---", file_name, body, file_name)
+    })
+}
+
+/// Copies an attachment into the attachments folder; called once the
+/// message it belongs to is stored. The name carries the message id and the
+/// attachment's position, so files with the same name never overwrite each other.
+pub fn store_copy(
+    source_path: &Path,
+    message_id: i64,
+    index: usize,
+    file_name: &str,
+) -> Result<PathBuf, String> {
+    let dir = get_attachments_dir()?;
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create attachments directory: {}", e))?;
+
+    let stored_path = dir.join(format!(
+        "{}_{}-{}_{}",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S"),
+        message_id,
+        index,
+        file_name
+    ));
+    std::fs::copy(source_path, &stored_path)
+        .map_err(|e| format!("Failed to save attachment {}: {}", file_name, e))?;
+    Ok(stored_path)
+}
+
+/// Deletes stored attachment copies whose rows were removed. Paths outside
+/// the attachments folder are left alone.
+pub fn remove_stored_copies(stored_paths: &[String]) {
+    let Ok(dir) = get_attachments_dir() else {
+        return;
+    };
+    for stored_path in stored_paths {
+        let path = Path::new(stored_path);
+        if !path.starts_with(&dir) {
+            continue;
+        }
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("[attachments] Failed to remove {}: {}", stored_path, e);
+            }
+        }
+    }
+}
+
+/// Pulls the text out of a PDF's page content streams.
+///
+/// This is a best-effort reader for the common case of Flate-compressed
+/// streams drawing literal or hex strings; scanned pages and fonts with
+/// custom encodings come back empty or garbled.
+pub fn extract_pdf_text(pdf: &[u8]) -> String {
+    let mut text = String::new();
+    let mut rest = pdf;
+
+    while let Some(keyword) = find(rest, b"stream") {
+        // The stream's dictionary is everything between its object header and
+        // the keyword; searching for `<<` instead would stop at a nested
+        // dictionary such as /DecodeParms
+        let dict = &rest[rfind(&rest[..keyword], b"obj").unwrap_or(0)..keyword];
+
+        // The data starts after the end-of-line that follows the keyword
+        let mut start = keyword + b"stream".len();
+        if rest[start..].starts_with(b"\r\n") {
+            start += 2;
+        } else if rest[start..].starts_with(b"\n") || rest[start..].starts_with(b"\r") {
+            start += 1;
+        }
+        // A truncated file still yields what it has of its last stream
+        let length = find(&rest[start..], b"endstream").unwrap_or(rest.len() - start);
+        let data = &rest[start..start + length];
+
+        let is_content = !NON_CONTENT_STREAM_KEYS
+            .iter()
+            .any(|key| find(dict, key).is_some());
+        if is_content {
+            let content = if find(dict, b"/FlateDecode").is_some() {
+                inflate(data)
+            } else if find(dict, b"/Filter").is_none() {
+                Some(data.to_vec())
+            } else {
+                None
+            };
+            if let Some(content) = content {
+                extract_text_operators(&content, &mut text);
+            }
+        }
+
+        rest = rest
+            .get(start + length + b"endstream".len()..)
+            .unwrap_or_default();
+    }
+
+    text
+}
+
+/// Decompresses a Flate stream, keeping whatever decoded before any error
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let result = ZlibDecoder::new(data).read_to_end(&mut decoded);
+    if result.is_err() && decoded.is_empty() {
+        return None;
+    }
+    Some(decoded)
+}
+
+/// Appends the strings shown by text operators (Tj, TJ, ', ") in a content stream
+fn extract_text_operators(content: &[u8], out: &mut String) {
+    let mut pending = String::new();
+    let mut numbers: Vec<f64> = Vec::new();
+    let mut in_array = false;
+    let mut i = 0;
+
+    while i < content.len() {
+        let c = content[i];
+        match c {
+            b'(' => {
+                let (string, end) = read_literal_string(content, i + 1);
+                pending.push_str(&string);
+                i = end;
+                continue;
+            }
+            b'<' if content.get(i + 1) != Some(&b'<') => {
+                let end = content[i..]
+                    .iter()
+                    .position(|b| *b == b'>')
+                    .map_or(content.len(), |p| i + p);
+                pending.push_str(&decode_hex_string(&content[i + 1..end]));
+                i = end + 1;
+                continue;
+            }
+            b'[' => in_array = true,
+            b']' => in_array = false,
+            b'%' => {
+                // Comment until end of line
+                while i < content.len() && content[i] != b'\n' && content[i] != b'\r' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'\'' | b'"' => {
+                out.push('\n');
+                out.push_str(&pending);
+                pending.clear();
+            }
+            _ if c.is_ascii_digit() || c == b'-' || c == b'+' || c == b'.' => {
+                let end = content[i..]
+                    .iter()
+                    .position(|b| !(b.is_ascii_digit() || matches!(b, b'-' | b'+' | b'.')))
+                    .map_or(content.len(), |p| i + p);
+                let number = std::str::from_utf8(&content[i..end])
+                    .ok()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .unwrap_or(0.0);
+                // Large negative kerning inside a TJ array stands in for a space
+                if in_array && number < -200.0 {
+                    pending.push(' ');
+                }
+                numbers.push(number);
+                i = end;
+                continue;
+            }
+            _ if c.is_ascii_alphabetic() || c == b'*' => {
+                let end = content[i..]
+                    .iter()
+                    .position(|b| !(b.is_ascii_alphabetic() || *b == b'*'))
+                    .map_or(content.len(), |p| i + p);
+                match &content[i..end] {
+                    b"Tj" | b"TJ" => out.push_str(&pending),
+                    b"T*" | b"ET" => out.push('\n'),
+                    // Moving to a new line; a purely horizontal move is a word gap
+                    b"Td" | b"TD" => match numbers.last() {
+                        Some(y) if *y != 0.0 => out.push('\n'),
+                        _ => out.push(' '),
+                    },
+                    _ => {}
+                }
+                pending.clear();
+                numbers.clear();
+                i = end;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+/// Reads a literal string starting just after its opening parenthesis.
+/// Returns the decoded text and the index just past the closing parenthesis.
+fn read_literal_string(content: &[u8], start: usize) -> (String, usize) {
+    let mut text = String::new();
+    let mut depth = 1;
+    let mut i = start;
+
+    while i < content.len() {
+        let c = content[i];
+        match c {
+            b'\\' => {
+                i += 1;
+                let Some(&escaped) = content.get(i) else {
+                    break;
+                };
+                match escaped {
+                    b'n' => text.push('\n'),
+                    b'r' => text.push('\r'),
+                    b't' => text.push('\t'),
+                    b'b' | b'f' => {}
+                    b'0'..=b'7' => {
+                        let digits = content[i..]
+                            .iter()
+                            .take(3)
+                            .take_while(|b| (b'0'..=b'7').contains(*b))
+                            .count();
+                        let code = std::str::from_utf8(&content[i..i + digits])
+                            .ok()
+                            .and_then(|s| u8::from_str_radix(s, 8).ok())
+                            .unwrap_or(b'?');
+                        text.push(code as char);
+                        i += digits;
+                        continue;
+                    }
+                    // Escaped line break continues the string
+                    b'\r' | b'\n' => {}
+                    other => text.push(other as char),
+                }
+            }
+            b'(' => {
+                depth += 1;
+                text.push('(');
+            }
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return (text, i + 1);
+                }
+                text.push(')');
+            }
+            // PDFDocEncoding matches Latin-1 for the printable range
+            other => text.push(other as char),
+        }
+        i += 1;
+    }
+
+    (text, i)
+}
+
+/// Decodes a hex string; two-byte strings are read as UTF-16BE, others as Latin-1
+fn decode_hex_string(hex: &[u8]) -> String {
+    let digits: Vec<u8> = hex
+        .iter()
+        .filter_map(|b| (*b as char).to_digit(16).map(|d| d as u8))
+        .collect();
+    let bytes: Vec<u8> = digits
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+        .collect();
+
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = bytes[2..]
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+            .collect();
+        return String::from_utf16_lossy(&units);
+    }
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLATE_PDF: &[u8] = include_bytes!("../tests/fixtures/flate.pdf");
+    const UNCOMPRESSED_PDF: &[u8] = include_bytes!("../tests/fixtures/uncompressed.pdf");
+    const TRUNCATED_PDF: &[u8] = include_bytes!("../tests/fixtures/truncated.pdf");
+
+    #[test]
+    fn extracts_flate_compressed_pages() {
+        // The content stream also carries a nested /DecodeParms dictionary
+        assert_eq!(
+            extract_pdf_text(FLATE_PDF).trim(),
+            "Hello, world!\nSecond line"
+        );
+    }
+
+    #[test]
+    fn skips_font_programs_and_object_streams() {
+        let text = extract_pdf_text(FLATE_PDF);
+        assert!(!text.contains("Garbage"), "{:?}", text);
+        assert!(!text.contains("Not page text"), "{:?}", text);
+    }
+
+    #[test]
+    fn extracts_uncompressed_pages() {
+        assert_eq!(
+            extract_pdf_text(UNCOMPRESSED_PDF).trim(),
+            "Kern ingworks\ncaf\u{e9} (nested (parens)) tab\there\nHello, hex\n\u{dc}ni\nNext line"
+        );
+    }
+
+    #[test]
+    fn keeps_what_decoded_from_a_truncated_stream() {
+        assert_eq!(
+            extract_pdf_text(TRUNCATED_PDF).trim(),
+            "The first paragraph arrived intact."
+        );
+    }
+
+    #[test]
+    fn turns_wide_kerning_into_spaces() {
+        let mut text = String::new();
+        extract_text_operators(b"[(A)-100(B)-300(C)] TJ", &mut text);
+        assert_eq!(text, "AB C");
+    }
+
+    #[test]
+    fn decodes_literal_string_escapes() {
+        // Escaped backslash and parentheses, octal codes and a line continuation
+        let content = b"(a\\\\b \\(c\\) \\101\\7 line\\\ncont)rest";
+        let (text, end) = read_literal_string(content, 1);
+        assert_eq!(text, "a\\b (c) A\u{7} linecont");
+        assert_eq!(&content[end..], b"rest");
+
+        // An unterminated string runs to the end of the content
+        assert_eq!(read_literal_string(b"(open", 1), ("open".to_string(), 5));
+    }
+
+    #[test]
+    fn decodes_hex_strings() {
+        assert_eq!(decode_hex_string(b"48 69"), "Hi");
+        // An odd digit count is padded with zero
+        assert_eq!(decode_hex_string(b"4"), "@");
+        assert_eq!(decode_hex_string(b"FEFF00480069D83DDE00"), "Hi\u{1F600}");
+    }
+}
@@ -0,0 +1,609 @@
+//! Model catalog for the model pickers
+//!
+//! The OpenRouter list is large and rarely changes, so it is cached on disk
+//! and only downloaded again once the cache is older than a day. When
+//! OpenRouter can't be reached, a stale cache is used instead. Local models
+//! are always listed live so a newly pulled model shows up straight away.
+
+use crate::llm::OPENROUTER_BASE_URL;
+use crate::paths::get_model_catalog_path;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a downloaded OpenRouter catalog is used before refreshing it
+const CATALOG_TTL_SECS: i64 = 24 * 60 * 60;
+
+/// Longest a model list request may take before giving up
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Price of a model in USD per million tokens
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ModelPricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModelOption {
+    pub id: String,
+    pub name: String,
+    pub supports_vision: bool,
+    pub context_length: Option<u32>,
+    /// `None` when the price is unknown or varies per request
+    #[serde(default)]
+    pub pricing: Option<ModelPricing>,
+    #[serde(default)]
+    pub input_modalities: Vec<String>,
+    #[serde(default)]
+    pub output_modalities: Vec<String>,
+    /// Whether the model accepts tool definitions
+    #[serde(default)]
+    pub supports_tools: bool,
+    /// Who makes the model ("anthropic" for "anthropic/claude-3.5-sonnet"),
+    /// or "local" for models served by the local server
+    #[serde(default)]
+    pub provider: String,
+}
+
+/// OpenRouter catalog as saved on disk
+#[derive(Serialize, Deserialize)]
+struct CachedCatalog {
+    /// Unix timestamp of the download
+    fetched_at: i64,
+    models: Vec<ModelOption>,
+}
+
+impl CachedCatalog {
+    fn is_fresh(&self) -> bool {
+        chrono::Utc::now().timestamp() - self.fetched_at < CATALOG_TTL_SECS
+    }
+}
+
+/// OpenRouter catalog loaded from disk or downloaded this session
+static OPENROUTER_CATALOG: Mutex<Option<CachedCatalog>> = Mutex::new(None);
+
+/// Models from the last listing, local ones included, for lookups by id
+static LISTED_MODELS: Mutex<Vec<ModelOption>> = Mutex::new(Vec::new());
+
+/// When models were last listed, or listing last failed, so lookups don't
+/// go to the network on every message
+static LISTING_ATTEMPTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Returns the OpenRouter models, downloading them when the cache is missing,
+/// expired or `refresh` is set. A stale cache stands in when the download fails.
+pub async fn openrouter_models(refresh: bool) -> Result<Vec<ModelOption>, String> {
+    if OPENROUTER_CATALOG.lock().unwrap().is_none() {
+        *OPENROUTER_CATALOG.lock().unwrap() = read_cache();
+    }
+    if !refresh {
+        if let Some(cached) = OPENROUTER_CATALOG.lock().unwrap().as_ref() {
+            if cached.is_fresh() {
+                return Ok(cached.models.clone());
+            }
+        }
+    }
+
+    match fetch_openrouter_models().await {
+        Ok(models) => {
+            let catalog = CachedCatalog {
+                fetched_at: chrono::Utc::now().timestamp(),
+                models: models.clone(),
+            };
+            if let Err(e) = write_cache(&catalog) {
+                warn!("[catalog] {}", e);
+            }
+            *OPENROUTER_CATALOG.lock().unwrap() = Some(catalog);
+            Ok(models)
+        }
+        Err(e) => match OPENROUTER_CATALOG.lock().unwrap().as_ref() {
+            Some(cached) => {
+                warn!("[catalog] Using cached model list: {}", e);
+                Ok(cached.models.clone())
+            }
+            None => Err(e),
+        },
+    }
+}
+
+fn read_cache() -> Option<CachedCatalog> {
+    let content = std::fs::read_to_string(get_model_catalog_path().ok()?).ok()?;
+    match serde_json::from_str(&content) {
+        Ok(catalog) => Some(catalog),
+        Err(e) => {
+            warn!("[catalog] Ignoring unreadable model cache: {}", e);
+            None
+        }
+    }
+}
+
+fn write_cache(catalog: &CachedCatalog) -> Result<(), String> {
+    let path = get_model_catalog_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+    let content = serde_json::to_string(catalog)
+        .map_err(|e| format!("Failed to serialize model cache: {}", e))?;
+    std::fs::write(&path, content).map_err(|e| format!("Failed to save model cache: {}", e))
+}
+
+/// Remembers a full listing for later lookups by id
+pub fn remember_listing(models: &[ModelOption]) {
+    *LISTED_MODELS.lock().unwrap() = models.to_vec();
+    *LISTING_ATTEMPTED_AT.lock().unwrap() = Some(Instant::now());
+}
+
+/// Remembers that listing failed, holding off lookups' retries
+pub fn remember_listing_failure() {
+    *LISTING_ATTEMPTED_AT.lock().unwrap() = Some(Instant::now());
+}
+
+/// Whether lookups should list the models first: nothing was listed, or
+/// tried to be, within a catalog TTL
+pub fn needs_listing() -> bool {
+    LISTING_ATTEMPTED_AT
+        .lock()
+        .unwrap()
+        .is_none_or(|at| at.elapsed().as_secs() >= CATALOG_TTL_SECS as u64)
+}
+
+fn http_client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+/// Finds a listed model by id. Direct providers use bare ids ("gpt-4o")
+/// where OpenRouter has "openai/gpt-4o", so those match by suffix.
+pub fn find_listed(model_id: &str) -> Option<ModelOption> {
+    let suffix = format!("/{}", model_id);
+    let listed = LISTED_MODELS.lock().unwrap();
+    listed
+        .iter()
+        .find(|m| m.id == model_id)
+        .or_else(|| listed.iter().find(|m| m.id.ends_with(&suffix)))
+        .cloned()
+}
+
+/// Fetches the model list from the OpenRouter API
+async fn fetch_openrouter_models() -> Result<Vec<ModelOption>, String> {
+    let client = http_client()?;
+    let response = client
+        .get(format!("{}/models", OPENROUTER_BASE_URL))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch models: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(format!("OpenRouter API error: {}", response.status()));
+    }
+
+    let json: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse models response: {}", e))?;
+
+    let models = json["data"]
+        .as_array()
+        .ok_or_else(|| "Invalid response format".to_string())?;
+
+    Ok(models.iter().filter_map(parse_openrouter_model).collect())
+}
+
+fn parse_openrouter_model(m: &Value) -> Option<ModelOption> {
+    let id = m["id"].as_str()?.to_string();
+    let name = m["name"].as_str().unwrap_or(&id).to_string();
+
+    let string_list = |value: &Value| -> Vec<String> {
+        value
+            .as_array()
+            .map(|list| {
+                list.iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let input_modalities = string_list(&m["architecture"]["input_modalities"]);
+    let output_modalities = string_list(&m["architecture"]["output_modalities"]);
+    let supports_tools = string_list(&m["supported_parameters"])
+        .iter()
+        .any(|p| p == "tools");
+
+    Some(ModelOption {
+        supports_vision: input_modalities.iter().any(|m| m == "image"),
+        context_length: m["context_length"].as_u64().map(|n| n as u32),
+        pricing: parse_pricing(&m["pricing"]),
+        input_modalities,
+        output_modalities,
+        supports_tools,
+        provider: id.split_once('/').map_or("", |(p, _)| p).to_string(),
+        id,
+        name,
+    })
+}
+
+/// Converts OpenRouter's per-token price strings to USD per million tokens.
+/// Routers like "openrouter/auto" report -1 because the price depends on the
+/// model they pick.
+fn parse_pricing(pricing: &Value) -> Option<ModelPricing> {
+    let per_million = |value: &Value| {
+        value
+            .as_str()?
+            .parse::<f64>()
+            .ok()
+            .filter(|p| *p >= 0.0)
+            .map(|p| p * 1_000_000.0)
+    };
+    Some(ModelPricing {
+        prompt: per_million(&pricing["prompt"])?,
+        completion: per_million(&pricing["completion"])?,
+    })
+}
+
+/// Fetches the model list from a local server, trying the OpenAI-compatible
+/// `/v1/models` endpoint first and Ollama's native `/api/tags` second
+pub async fn fetch_local_models(base_url: &str) -> Result<Vec<ModelOption>, String> {
+    let client = http_client()?;
+
+    let openai_style = client
+        .get(format!("{}/v1/models", base_url))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach local server at {}: {}", base_url, e))?;
+
+    if openai_style.status().is_success() {
+        if let Ok(json) = openai_style.json::<Value>().await {
+            if let Some(models) = json["data"].as_array() {
+                return Ok(models
+                    .iter()
+                    .filter_map(|m| {
+                        let id = m["id"].as_str()?.to_string();
+                        // The OpenAI models endpoint carries no modality info
+                        Some(local_model(id, false))
+                    })
+                    .collect());
+            }
+        }
+    }
+
+    let response = client
+        .get(format!("{}/api/tags", base_url))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch local models: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(format!("Local server error: {}", response.status()));
+    }
+
+    let json: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse local models response: {}", e))?;
+
+    let models = json["models"]
+        .as_array()
+        .ok_or_else(|| "Invalid local models response format".to_string())?;
+
+    Ok(models
+        .iter()
+        .filter_map(|m| {
+            let id = m["name"].as_str()?.to_string();
+
+            // Ollama vision models (llava, llama3.2-vision, ...) ship a CLIP projector
+            let supports_vision = m["details"]["families"]
+                .as_array()
+                .map(|families| {
+                    families
+                        .iter()
+                        .any(|f| matches!(f.as_str(), Some("clip") | Some("mllama")))
+                })
+                .unwrap_or(false);
+
+            Some(local_model(id, supports_vision))
+        })
+        .collect())
+}
+
+fn local_model(id: String, supports_vision: bool) -> ModelOption {
+    let mut input_modalities = vec!["text".to_string()];
+    if supports_vision {
+        input_modalities.push("image".to_string());
+    }
+    ModelOption {
+        name: format!("{} (local)", id),
+        id,
+        supports_vision,
+        context_length: None,
+        // Running a local model costs nothing per token
+        pricing: Some(ModelPricing::default()),
+        input_modalities,
+        output_modalities: vec!["text".to_string()],
+        // Tool support depends on the model and server, so don't promise it
+        supports_tools: false,
+        provider: "local".to_string(),
+    }
+}
+
+/// Order of search results
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelSort {
+    /// Alphabetical, with favorites first
+    #[default]
+    Name,
+    /// Cheapest first by prompt plus completion price; unknown prices last
+    Price,
+    /// Longest context first
+    ContextLength,
+}
+
+/// Narrows a model search; unset fields don't filter
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ModelFilters {
+    pub vision: bool,
+    pub tools: bool,
+    pub min_context_length: Option<u32>,
+    /// USD per million prompt tokens
+    pub max_prompt_price: Option<f64>,
+    /// USD per million completion tokens
+    pub max_completion_price: Option<f64>,
+    pub provider: Option<String>,
+    pub favorites_only: bool,
+    pub sort: ModelSort,
+}
+
+/// Filters and orders models. Every word of the query has to appear in the
+/// model's id or name.
+pub fn search(
+    models: &[ModelOption],
+    query: &str,
+    filters: &ModelFilters,
+    favorites: &[String],
+) -> Vec<ModelOption> {
+    let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    let is_favorite = |m: &ModelOption| favorites.contains(&m.id);
+    let within = |price: Option<f64>, max: Option<f64>| match max {
+        Some(max) => price.is_some_and(|p| p <= max),
+        None => true,
+    };
+
+    let mut results: Vec<ModelOption> = models
+        .iter()
+        .filter(|m| {
+            let haystack = format!("{} {}", m.id, m.name).to_lowercase();
+            terms.iter().all(|t| haystack.contains(t))
+        })
+        .filter(|m| !filters.vision || m.supports_vision)
+        .filter(|m| !filters.tools || m.supports_tools)
+        .filter(|m| {
+            filters
+                .min_context_length
+                .is_none_or(|min| m.context_length.is_some_and(|c| c >= min))
+        })
+        .filter(|m| {
+            within(
+                m.pricing.as_ref().map(|p| p.prompt),
+                filters.max_prompt_price,
+            )
+        })
+        .filter(|m| {
+            within(
+                m.pricing.as_ref().map(|p| p.completion),
+                filters.max_completion_price,
+            )
+        })
+        .filter(|m| {
+            filters
+                .provider
+                .as_ref()
+                .is_none_or(|p| m.provider.eq_ignore_ascii_case(p))
+        })
+        .filter(|m| !filters.favorites_only || is_favorite(m))
+        .cloned()
+        .collect();
+
+    match filters.sort {
+        ModelSort::Name => results.sort_by_key(|m| (!is_favorite(m), m.name.to_lowercase())),
+        ModelSort::Price => results.sort_by(|a, b| {
+            let total = |m: &ModelOption| m.pricing.as_ref().map(|p| p.prompt + p.completion);
+            match (total(a), total(b)) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (a, b) => a.is_none().cmp(&b.is_none()),
+            }
+        }),
+        ModelSort::ContextLength => {
+            results.sort_by_key(|m| std::cmp::Reverse(m.context_length.unwrap_or(0)))
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(
+        id: &str,
+        vision: bool,
+        tools: bool,
+        context_length: Option<u32>,
+        pricing: Option<(f64, f64)>,
+    ) -> ModelOption {
+        ModelOption {
+            id: id.to_string(),
+            name: id.rsplit('/').next().unwrap().to_uppercase(),
+            supports_vision: vision,
+            context_length,
+            pricing: pricing.map(|(prompt, completion)| ModelPricing { prompt, completion }),
+            input_modalities: Vec::new(),
+            output_modalities: Vec::new(),
+            supports_tools: tools,
+            provider: id.split_once('/').map_or("local", |(p, _)| p).to_string(),
+        }
+    }
+
+    fn catalog() -> Vec<ModelOption> {
+        vec![
+            model(
+                "openai/gpt-4o",
+                true,
+                true,
+                Some(128_000),
+                Some((2.5, 10.0)),
+            ),
+            model(
+                "openai/gpt-4o-mini",
+                true,
+                true,
+                Some(128_000),
+                Some((0.15, 0.6)),
+            ),
+            model(
+                "anthropic/claude-3-haiku",
+                true,
+                false,
+                Some(200_000),
+                Some((0.25, 1.25)),
+            ),
+            model("openrouter/auto", false, true, None, None),
+            model("llama3", false, false, None, Some((0.0, 0.0))),
+        ]
+    }
+
+    fn ids(models: &[ModelOption]) -> Vec<&str> {
+        models.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    fn search_with(query: &str, filters: ModelFilters) -> Vec<ModelOption> {
+        search(&catalog(), query, &filters, &[])
+    }
+
+    #[test]
+    fn every_query_word_must_match_id_or_name() {
+        assert_eq!(
+            ids(&search_with("GPT mini", ModelFilters::default())),
+            vec!["openai/gpt-4o-mini"]
+        );
+        assert_eq!(
+            ids(&search_with("openai 4O", ModelFilters::default())),
+            vec!["openai/gpt-4o", "openai/gpt-4o-mini"]
+        );
+        assert!(search_with("gemini", ModelFilters::default()).is_empty());
+    }
+
+    #[test]
+    fn filters_by_capability_and_provider() {
+        let vision_tools = ModelFilters {
+            vision: true,
+            tools: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&search_with("", vision_tools)),
+            vec!["openai/gpt-4o", "openai/gpt-4o-mini"]
+        );
+
+        let anthropic = ModelFilters {
+            provider: Some("Anthropic".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&search_with("", anthropic)),
+            vec!["anthropic/claude-3-haiku"]
+        );
+    }
+
+    #[test]
+    fn unknown_values_fail_numeric_filters() {
+        let long_context = ModelFilters {
+            min_context_length: Some(150_000),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&search_with("", long_context)),
+            vec!["anthropic/claude-3-haiku"]
+        );
+
+        let cheap = ModelFilters {
+            max_prompt_price: Some(0.2),
+            max_completion_price: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&search_with("", cheap)),
+            vec!["openai/gpt-4o-mini", "llama3"]
+        );
+    }
+
+    #[test]
+    fn name_sort_puts_favorites_first() {
+        let favorites = vec!["openai/gpt-4o-mini".to_string(), "llama3".to_string()];
+        let results = search(&catalog(), "", &ModelFilters::default(), &favorites);
+        assert_eq!(
+            ids(&results),
+            vec![
+                "openai/gpt-4o-mini",
+                "llama3",
+                "openrouter/auto",
+                "anthropic/claude-3-haiku",
+                "openai/gpt-4o",
+            ]
+        );
+
+        let favorites_only = ModelFilters {
+            favorites_only: true,
+            ..Default::default()
+        };
+        assert_eq!(search(&catalog(), "", &favorites_only, &favorites).len(), 2);
+    }
+
+    #[test]
+    fn price_sort_puts_unknown_prices_last() {
+        let by_price = ModelFilters {
+            sort: ModelSort::Price,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&search_with("", by_price)),
+            vec![
+                "llama3",
+                "openai/gpt-4o-mini",
+                "anthropic/claude-3-haiku",
+                "openai/gpt-4o",
+                "openrouter/auto",
+            ]
+        );
+    }
+
+    #[test]
+    fn context_sort_puts_longest_first() {
+        let by_context = ModelFilters {
+            sort: ModelSort::ContextLength,
+            ..Default::default()
+        };
+        let results = search_with("", by_context);
+        assert_eq!(results[0].id, "anthropic/claude-3-haiku");
+        assert!(results[3..].iter().all(|m| m.context_length.is_none()));
+    }
+
+    #[test]
+    fn parses_openrouter_pricing() {
+        let priced = serde_json::json!({ "prompt": "0.000003", "completion": "0.000015" });
+        let pricing = parse_pricing(&priced).unwrap();
+        assert!((pricing.prompt - 3.0).abs() < 1e-9);
+        assert!((pricing.completion - 15.0).abs() < 1e-9);
+
+        let routed = serde_json::json!({ "prompt": "-1", "completion": "-1" });
+        assert_eq!(parse_pricing(&routed), None);
+        assert_eq!(parse_pricing(&Value::Null), None);
+    }
+}
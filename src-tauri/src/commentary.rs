@@ -0,0 +1,143 @@
+//! Parsing of the character's commentary on assistant replies
+//!
+//! The character prompt asks for a bare JSON array of strings. Models often
+//! wrap it in a code fence or leave a trailing comma, so those two mistakes
+//! are repaired; anything else is rejected.
+
+use serde_json::Value;
+
+/// Most comments kept from one reply
+pub const MAX_COMMENTS: usize = 5;
+
+/// Parses the commentary model's output into a list of comments
+pub fn parse_comment_array(text: &str) -> Result<Vec<String>, String> {
+    let body = strip_code_fence(text.trim());
+    if !(body.starts_with('[') && body.ends_with(']')) {
+        return Err("Commentary is not a JSON array".to_string());
+    }
+
+    let values: Vec<Value> = serde_json::from_str(&remove_trailing_commas(body))
+        .map_err(|e| format!("Failed to parse commentary: {}", e))?;
+
+    let mut comments = Vec::with_capacity(values.len());
+    for value in values {
+        match value {
+            Value::String(comment) if !comment.trim().is_empty() => {
+                comments.push(comment.trim().to_string())
+            }
+            Value::String(_) => {}
+            other => return Err(format!("Commentary item is not a string: {}", other)),
+        }
+    }
+    comments.truncate(MAX_COMMENTS);
+    Ok(comments)
+}
+
+/// Removes a surrounding ``` or ```json fence
+fn strip_code_fence(text: &str) -> &str {
+    let Some(rest) = text.strip_prefix("```") else {
+        return text;
+    };
+    let body = match rest.split_once('\n') {
+        // Skip the info string ("json") on the opening line
+        Some((_, body)) => body,
+        // A fence on one line has the info string right before the body
+        None => rest.trim_start_matches(|c: char| c.is_ascii_alphanumeric()),
+    };
+    body.trim_end().strip_suffix("```").unwrap_or(body).trim()
+}
+
+/// Drops commas that directly precede a closing bracket or brace,
+/// leaving commas inside string literals alone
+fn remove_trailing_commas(json: &str) -> String {
+    let chars: Vec<char> = json.chars().collect();
+    let mut result = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, &c) in chars.iter().enumerate() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = chars[i + 1..].iter().find(|c| !c.is_whitespace());
+            if matches!(next, Some(']') | Some('}')) {
+                continue;
+            }
+        }
+        result.push(c);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bare_array() {
+        assert_eq!(
+            parse_comment_array(r#"["Nice!", "  Hmm.  ", ""]"#).unwrap(),
+            vec!["Nice!", "Hmm."]
+        );
+    }
+
+    #[test]
+    fn strips_code_fences() {
+        let expected = vec!["a", "b"];
+        assert_eq!(
+            parse_comment_array("```json\n[\"a\", \"b\"]\n```").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_comment_array("```\n[\"a\", \"b\"]\n```").unwrap(),
+            expected
+        );
+        assert_eq!(parse_comment_array(r#"```["a","b"]```"#).unwrap(), expected);
+        assert_eq!(
+            parse_comment_array(r#"```json["a","b"]```"#).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn repairs_trailing_commas() {
+        assert_eq!(
+            parse_comment_array("[\"a\", \"b\",\n]").unwrap(),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            remove_trailing_commas(r#"{"a": [1, 2, ], }"#),
+            r#"{"a": [1, 2 ] }"#
+        );
+    }
+
+    #[test]
+    fn keeps_commas_inside_strings() {
+        assert_eq!(
+            parse_comment_array(r#"["wait, ]", "say \"hi,\" ]",]"#).unwrap(),
+            vec!["wait, ]", "say \"hi,\" ]"]
+        );
+    }
+
+    #[test]
+    fn keeps_at_most_five_comments() {
+        let comments = parse_comment_array(r#"["1","2","3","4","5","6","7"]"#).unwrap();
+        assert_eq!(comments, vec!["1", "2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn rejects_other_output() {
+        assert!(parse_comment_array("Sure! Here you go.").is_err());
+        assert!(parse_comment_array(r#"["ok", 3]"#).is_err());
+        assert!(parse_comment_array(r#"{"comments": []}"#).is_err());
+    }
+}
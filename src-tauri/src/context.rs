@@ -0,0 +1,322 @@
+//! Token-budgeted context window construction for chat requests
+
+use crate::models::ChatMessage;
+use serde_json::{json, Value};
+
+/// Rough number of characters per token used for estimation
+const CHARS_PER_TOKEN: u32 = 4;
+
+/// Fixed per-message overhead for role markers and separators
+const MESSAGE_OVERHEAD_TOKENS: u32 = 4;
+
+/// Estimated cost of one attached screenshot
+pub const IMAGE_TOKENS: u32 = 1100;
+
+/// Context length assumed when a remote model's limit is unknown
+pub const DEFAULT_CONTEXT_LENGTH: u32 = 8192;
+
+/// Context length assumed for local models, which usually run with small windows
+pub const DEFAULT_LOCAL_CONTEXT_LENGTH: u32 = 4096;
+
+/// Result of fitting chat history into a token budget
+#[derive(Debug, Clone)]
+pub struct ContextWindow {
+    /// Messages ready to send: system prompt, history, then the current message
+    pub messages: Vec<Value>,
+    /// Estimated prompt size of `messages`
+    pub estimated_tokens: u32,
+    /// Number of history messages that fit into the budget
+    pub history_included: usize,
+    /// Id of the oldest history message that made it into the window
+    pub oldest_included_id: Option<i64>,
+}
+
+/// Estimates the token count of a piece of text
+pub fn estimate_text_tokens(text: &str) -> u32 {
+    (text.chars().count() as u32).div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Estimates the token count of an OpenAI-style message, including images
+pub fn estimate_message_tokens(message: &Value) -> u32 {
+    let content_tokens = match &message["content"] {
+        Value::String(text) => estimate_text_tokens(text),
+        Value::Array(parts) => parts
+            .iter()
+            .map(|part| match part["type"].as_str() {
+                Some("image_url") => IMAGE_TOKENS,
+                _ => estimate_text_tokens(part["text"].as_str().unwrap_or_default()),
+            })
+            .sum(),
+        _ => 0,
+    };
+    content_tokens + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// Stored roles that make up a context level's history
+pub fn history_roles(context_level: u8) -> &'static [&'static str] {
+    match context_level {
+        // Level 1: User + character (character's own history)
+        1 => &["user", "character"],
+        // Level 0: User + assistant only (clean assistant mode)
+        _ => &["user", "assistant"],
+    }
+}
+
+/// Maps a stored message to the API role it is sent with at a context level,
+/// or `None` if the message does not belong in that level's history
+pub fn api_role_for_level(msg: &ChatMessage, context_level: u8) -> Option<&'static str> {
+    if !history_roles(context_level).contains(&msg.role.as_str()) {
+        return None;
+    }
+    // Providers reject empty turns, e.g. a reply cancelled before any text
+    if msg.content.trim().is_empty() {
+        return None;
+    }
+    // Character comments are about assistant replies that the character's
+    // history leaves out, so replaying them would make no sense
+    if msg.parent_id.is_some() {
+        return None;
+    }
+    // Character messages become assistant role for API compatibility
+    match msg.role.as_str() {
+        "user" => Some("user"),
+        _ => Some("assistant"),
+    }
+}
+
+/// Builds the messages array, filling history newest-first until the budget is spent.
+///
+/// `history` must be in chronological order. The system prompt, the rolling
+/// summary (if any) and the current message are always included; `budget` is
+/// the prompt budget with room for the reply already subtracted.
+pub fn build_context_window(
+    system_prompt: &str,
+    summary: Option<&str>,
+    history: &[ChatMessage],
+    current_message: Value,
+    context_level: u8,
+    budget: u32,
+) -> ContextWindow {
+    let system_message = json!({
+        "role": "system",
+        "content": system_prompt
+    });
+
+    // Older turns that fell out of the window are carried forward as a summary
+    let summary_message = summary.map(|text| {
+        json!({
+            "role": "system",
+            "content": format!("Summary of the earlier conversation:\n{}", text)
+        })
+    });
+
+    let mut used =
+        estimate_message_tokens(&system_message) + estimate_message_tokens(&current_message);
+    if let Some(ref message) = summary_message {
+        used += estimate_message_tokens(message);
+    }
+
+    // Walk backwards from the newest message and stop at the first one that
+    // does not fit, so the included history stays contiguous
+    let mut selected: Vec<Value> = Vec::new();
+    let mut oldest_included_id = None;
+    for msg in history.iter().rev() {
+        let Some(role) = api_role_for_level(msg, context_level) else {
+            continue;
+        };
+
+        let candidate = json!({
+            "role": role,
+            "content": msg.content
+        });
+        let cost = estimate_message_tokens(&candidate);
+        if used + cost > budget {
+            break;
+        }
+
+        used += cost;
+        selected.push(candidate);
+        oldest_included_id = msg.id;
+    }
+    selected.reverse();
+
+    let history_included = selected.len();
+    let mut messages = Vec::with_capacity(history_included + 3);
+    messages.push(system_message);
+    messages.extend(summary_message);
+    messages.extend(selected);
+    messages.push(current_message);
+
+    ContextWindow {
+        messages,
+        estimated_tokens: used,
+        history_included,
+        oldest_included_id,
+    }
+}
+
+/// Renders stored messages as a plain transcript for the summarizer,
+/// keeping only the roles that belong to the context level
+pub fn format_transcript(messages: &[ChatMessage], context_level: u8) -> String {
+    messages
+        .iter()
+        .filter_map(|msg| {
+            let speaker = match api_role_for_level(msg, context_level)? {
+                "user" => "User",
+                _ => "Assistant",
+            };
+            Some(format!("{}: {}", speaker, msg.content))
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i64, role: &str, content: &str) -> ChatMessage {
+        serde_json::from_value(json!({
+            "id": id,
+            "timestamp": "2024-01-01T00:00:00Z",
+            "role": role,
+            "content": content,
+            "context_level": 0
+        }))
+        .unwrap()
+    }
+
+    fn contents(window: &ContextWindow) -> Vec<&str> {
+        window
+            .messages
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn estimates_text_and_images() {
+        assert_eq!(estimate_text_tokens(""), 0);
+        assert_eq!(estimate_text_tokens("abcde"), 2);
+        // Characters, not bytes
+        assert_eq!(estimate_text_tokens("ééééé"), 2);
+        let with_image = json!({
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "abcd" },
+                { "type": "image_url", "image_url": { "url": "data:" } }
+            ]
+        });
+        assert_eq!(
+            estimate_message_tokens(&with_image),
+            1 + IMAGE_TOKENS + MESSAGE_OVERHEAD_TOKENS
+        );
+    }
+
+    #[test]
+    fn fills_newest_first_and_stays_contiguous() {
+        let system = "s".repeat(40); // 14 tokens with overhead
+        let current = json!({ "role": "user", "content": "hi" }); // 5
+        let turn = "t".repeat(36); // 13
+        let history = vec![
+            message(1, "user", "x"),
+            message(2, "assistant", &"long ".repeat(80)),
+            message(3, "user", &turn),
+            message(4, "tool", &"ignored ".repeat(50)),
+            message(5, "character", &"other level ".repeat(50)),
+            message(6, "assistant", &turn),
+        ];
+
+        // Room for the last two turns and 10 more tokens: message 1 would
+        // still fit, but the long message 2 ends the window
+        let window = build_context_window(&system, None, &history, current, 0, 14 + 5 + 26 + 10);
+        assert_eq!(contents(&window), vec![system.as_str(), &turn, &turn, "hi"]);
+        assert_eq!(window.history_included, 2);
+        assert_eq!(window.oldest_included_id, Some(3));
+        assert_eq!(window.estimated_tokens, 14 + 5 + 26);
+    }
+
+    #[test]
+    fn always_includes_summary_and_current_message() {
+        let history = vec![
+            message(1, "user", "earlier"),
+            message(2, "assistant", "reply"),
+        ];
+        let current = json!({ "role": "user", "content": "now" });
+        let window = build_context_window("sys", Some("they said hi"), &history, current, 0, 0);
+
+        assert_eq!(
+            contents(&window),
+            vec![
+                "sys",
+                "Summary of the earlier conversation:\nthey said hi",
+                "now"
+            ]
+        );
+        assert_eq!(window.history_included, 0);
+        assert_eq!(window.oldest_included_id, None);
+        assert!(window.estimated_tokens > 0);
+    }
+
+    #[test]
+    fn budget_smaller_than_system_prompt_sends_no_history() {
+        let system = "s".repeat(400);
+        let history = vec![message(1, "user", "a"), message(2, "character", "b")];
+        let current = json!({ "role": "user", "content": "now" });
+        let window = build_context_window(&system, None, &history, current, 1, 10);
+
+        assert_eq!(window.messages.len(), 2);
+        assert_eq!(window.history_included, 0);
+        assert!(window.estimated_tokens > 10);
+    }
+
+    #[test]
+    fn sends_character_history_as_assistant_at_level_one() {
+        let history = vec![message(1, "user", "a"), message(2, "character", "b")];
+        let current = json!({ "role": "user", "content": "now" });
+        let window = build_context_window("sys", None, &history, current, 1, 1000);
+
+        let roles: Vec<&str> = window
+            .messages
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+    }
+
+    #[test]
+    fn skips_empty_cancelled_replies() {
+        let mut cancelled = message(2, "assistant", "");
+        cancelled.cancelled = true;
+        let history = vec![message(1, "user", "a"), cancelled, message(3, "user", "b")];
+        let current = json!({ "role": "user", "content": "now" });
+        let window = build_context_window("sys", None, &history, current, 0, 1000);
+
+        assert_eq!(contents(&window), vec!["sys", "a", "b", "now"]);
+        assert_eq!(window.history_included, 2);
+    }
+
+    #[test]
+    fn leaves_comments_on_assistant_replies_out_of_character_history() {
+        let mut comment = message(3, "character", "nice answer");
+        comment.parent_id = Some(2);
+        let history = vec![
+            message(1, "user", "question"),
+            message(2, "assistant", "answer"),
+            comment,
+            message(4, "user", "hi"),
+            message(5, "character", "hey"),
+        ];
+        let current = json!({ "role": "user", "content": "now" });
+        let window = build_context_window("sys", None, &history, current, 1, 1000);
+
+        assert_eq!(
+            contents(&window),
+            vec!["sys", "question", "hi", "hey", "now"]
+        );
+        assert_eq!(
+            format_transcript(&history, 1),
+            "User: question\n\nUser: hi\n\nAssistant: hey"
+        );
+    }
+}
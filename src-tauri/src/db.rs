@@ -1,87 +1,972 @@
 //! Database operations for chat history
+//!
+//! Queries run on the connections of a `Db`, which the app keeps in Tauri's
+//! managed state. The functions here block; async code runs them through
+//! `Db::run`.
 
-use crate::models::ChatMessage;
+use crate::attachments::remove_stored_copies;
+use crate::migrations;
+use crate::models::{
+    ChatMessage, ChatSearchHit, Conversation, ConversationSummary, HistoryFilters, HistoryPage,
+    MessageAttachment, NewChatMessage, Usage, UsageReportRow,
+};
 use crate::paths::get_db_path;
-use rusqlite::{params, Connection};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-/// Initializes the SQLite database, creating tables if needed
-pub fn init_database() -> Result<Connection, String> {
-    let db_path = get_db_path()?;
+/// How long a statement waits for another connection's lock before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// The chat database, kept in Tauri's managed state. Writes go through one
+/// connection and reads through another, so in WAL mode reads don't queue
+/// behind a write. Both connections open on first use.
+#[derive(Clone, Default)]
+pub struct Db {
+    connections: Arc<Connections>,
+}
+
+#[derive(Default)]
+struct Connections {
+    /// Database file; `None` uses the one in the app data directory
+    path: Option<PathBuf>,
+    writer: Mutex<Option<Connection>>,
+    reader: Mutex<Option<Connection>>,
+}
+
+impl Db {
+    /// A database kept in `path` instead of the app data directory, for tests
+    #[cfg(test)]
+    pub fn at(path: PathBuf) -> Db {
+        Db {
+            connections: Arc::new(Connections {
+                path: Some(path),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Runs `f` on the write connection, opening the database and applying
+    /// migrations on first use. This blocks; `f` must not call back into
+    /// another function here that writes.
+    fn write<T>(&self, f: impl FnOnce(&Connection) -> Result<T, String>) -> Result<T, String> {
+        let mut guard = self
+            .connections
+            .writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let conn = match &mut *guard {
+            Some(conn) => conn,
+            slot @ None => slot.insert(self.open(Access::Write)?),
+        };
+        f(conn)
+    }
+
+    /// Runs `f` on the read-only connection. This blocks like `write`.
+    fn read<T>(&self, f: impl FnOnce(&Connection) -> Result<T, String>) -> Result<T, String> {
+        let mut guard = self
+            .connections
+            .reader
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let conn = match &mut *guard {
+            Some(conn) => conn,
+            slot @ None => {
+                // The writer migrates the schema before anything reads it
+                self.write(|_| Ok(()))?;
+                slot.insert(self.open(Access::Read)?)
+            }
+        };
+        f(conn)
+    }
+
+    fn open(&self, access: Access) -> Result<Connection, String> {
+        let db_path = match &self.connections.path {
+            Some(path) => path.clone(),
+            None => get_db_path()?,
+        };
+        open_database(&db_path, access)
+    }
+
+    /// Closes both connections so the database file can be removed; the
+    /// next call reopens them
+    pub fn close(&self) {
+        for slot in [&self.connections.reader, &self.connections.writer] {
+            slot.lock().unwrap_or_else(|e| e.into_inner()).take();
+        }
+    }
+
+    /// Runs database work on the blocking thread pool with a handle to
+    /// this database
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Db) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let db = self.clone();
+        run_blocking(move || f(&db)).await
+    }
+}
+
+/// Which of a `Db`'s connections to open
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+}
+
+/// Opens a connection to the SQLite database. The write connection turns on
+/// WAL mode and applies any pending schema migrations.
+fn open_database(db_path: &Path, access: Access) -> Result<Connection, String> {
     // Ensure parent directory exists
     if let Some(parent) = db_path.parent() {
         std::fs::create_dir_all(parent)
             .map_err(|e| format!("Failed to create database directory: {}", e))?;
     }
 
-    let conn = Connection::open(&db_path).map_err(|e| format!("Failed to open database: {}", e))?;
+    let mut conn =
+        Connection::open(db_path).map_err(|e| format!("Failed to open database: {}", e))?;
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .map_err(|e| format!("Failed to set busy timeout: {}", e))?;
+    if access == Access::Read {
+        conn.pragma_update(None, "query_only", true)
+            .map_err(|e| format!("Failed to open read connection: {}", e))?;
+        return Ok(conn);
+    }
+    // Readers no longer wait on a writer, and commits don't fsync twice
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+        .map_err(|e| format!("Failed to enable WAL: {}", e))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")
+        .map_err(|e| format!("Failed to set synchronous mode: {}", e))?;
+    migrations::migrate(&mut conn)?;
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS chat_history (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            timestamp TEXT NOT NULL,
-            role TEXT NOT NULL,
-            content TEXT NOT NULL,
-            context_level INTEGER DEFAULT 0
-        )",
-        [],
+    Ok(conn)
+}
+
+/// Runs blocking work on the blocking thread pool so async commands don't
+/// hold up the runtime's worker threads while SQLite or the disk is busy
+pub async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    tauri::async_runtime::spawn_blocking(f)
+        .await
+        .map_err(|e| format!("Blocking task failed: {}", e))?
+}
+
+/// Stores a chat message in the database, returning its row id
+pub fn store_chat_message(db: &Db, message: &NewChatMessage) -> Result<i64, String> {
+    db.write(|conn| {
+        conn.execute(
+            "INSERT INTO chat_history (timestamp, role, content, context_level, cancelled, request_id, model, prompt_tokens, completion_tokens, cost, latency_ms, finish_reason, parent_id, reasoning, fallback_hops, conversation_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            params![
+                message.timestamp,
+                message.role,
+                message.content,
+                message.context_level,
+                message.cancelled,
+                message.request_id,
+                message.model,
+                message.usage.as_ref().map(|u| u.prompt_tokens),
+                message.usage.as_ref().map(|u| u.completion_tokens),
+                message.usage.as_ref().and_then(|u| u.cost),
+                message.latency_ms,
+                message.finish_reason,
+                message.parent_id,
+                message.reasoning,
+                if message.fallback_hops.is_empty() {
+                    None
+                } else {
+                    serde_json::to_string(&message.fallback_hops).ok()
+                },
+                message.conversation_id
+            ],
+        ).map_err(|e| format!("Failed to store message: {}", e))?;
+        let id = conn.last_insert_rowid();
+
+        conn.execute(
+            "UPDATE conversations SET updated_at = ?1 WHERE id = ?2",
+            params![message.timestamp, message.conversation_id],
+        )
+        .map_err(|e| format!("Failed to update conversation: {}", e))?;
+        Ok(id)
+    })
+}
+
+/// Columns selected for every ChatMessage query, in the order read by `row_to_chat_message`
+const CHAT_MESSAGE_COLUMNS: &str = "id, timestamp, role, content, COALESCE(context_level, 0), COALESCE(cancelled, 0), request_id, \
+     model, prompt_tokens, completion_tokens, cost, latency_ms, finish_reason, parent_id, reasoning, fallback_hops, conversation_id";
+
+fn row_to_chat_message(row: &Row) -> rusqlite::Result<ChatMessage> {
+    Ok(ChatMessage {
+        id: Some(row.get(0)?),
+        timestamp: row.get(1)?,
+        role: row.get(2)?,
+        content: row.get(3)?,
+        context_level: row.get::<_, i64>(4)? as u8,
+        cancelled: row.get::<_, i64>(5)? != 0,
+        request_id: row.get(6)?,
+        model: row.get(7)?,
+        // Rows written before usage accounting have no token counts
+        usage: match row.get::<_, Option<u32>>(8)? {
+            Some(prompt_tokens) => Some(Usage {
+                prompt_tokens,
+                completion_tokens: row.get::<_, Option<u32>>(9)?.unwrap_or(0),
+                cost: row.get(10)?,
+            }),
+            None => None,
+        },
+        latency_ms: row.get(11)?,
+        finish_reason: row.get(12)?,
+        parent_id: row.get(13)?,
+        reasoning: row.get(14)?,
+        fallback_hops: row
+            .get::<_, Option<String>>(15)?
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
+        conversation_id: row.get(16)?,
+    })
+}
+
+/// Retrieves the latest chat history of a conversation, or of all
+/// conversations when `conversation_id` is `None`
+pub fn get_chat_history_internal(
+    db: &Db,
+    conversation_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<ChatMessage>, String> {
+    db.read(|conn| {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM chat_history WHERE ?1 IS NULL OR conversation_id = ?1 ORDER BY id DESC LIMIT ?2",
+                CHAT_MESSAGE_COLUMNS
+            ))
+            .map_err(|e| format!("Failed to prepare query: {}", e))?;
+
+        let messages = stmt
+            .query_map(params![conversation_id, limit], row_to_chat_message)
+            .map_err(|e| format!("Failed to query: {}", e))?;
+
+        let mut result: Vec<ChatMessage> = messages.filter_map(|m| m.ok()).collect();
+
+        // Reverse to get chronological order
+        result.reverse();
+        Ok(result)
+    })
+}
+
+/// Retrieves the page of filtered messages before `before_id`, or the latest
+/// page when `None`, oldest first
+pub fn get_chat_history_page_internal(
+    db: &Db,
+    before_id: Option<i64>,
+    limit: i64,
+    filters: &HistoryFilters,
+) -> Result<HistoryPage, String> {
+    db.read(|conn| {
+        let mut values: Vec<Value> = vec![before_id.into()];
+        let filter_sql = history_filter_sql(filters, &mut values);
+        // One extra row tells whether an older page exists
+        values.push((limit + 1).into());
+
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM chat_history h WHERE (?1 IS NULL OR h.id < ?1){} ORDER BY h.id DESC LIMIT ?",
+                CHAT_MESSAGE_COLUMNS, filter_sql
+            ))
+            .map_err(|e| format!("Failed to prepare query: {}", e))?;
+
+        let mut messages = stmt
+            .query_map(params_from_iter(values), row_to_chat_message)
+            .map_err(|e| format!("Failed to query: {}", e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read history: {}", e))?;
+
+        let has_more = messages.len() as i64 > limit;
+        messages.truncate(limit as usize);
+        messages.reverse();
+        let next_before_id = if has_more {
+            messages.first().and_then(|m| m.id)
+        } else {
+            None
+        };
+
+        Ok(HistoryPage {
+            messages,
+            next_before_id,
+        })
+    })
+}
+
+/// Gets a single message by id
+pub fn get_message_internal(db: &Db, message_id: i64) -> Result<Option<ChatMessage>, String> {
+    db.read(|conn| load_message(conn, message_id))
+}
+
+fn load_message(conn: &Connection, message_id: i64) -> Result<Option<ChatMessage>, String> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM chat_history WHERE id = ?1",
+            CHAT_MESSAGE_COLUMNS
+        ),
+        params![message_id],
+        row_to_chat_message,
     )
-    .map_err(|e| format!("Failed to create table: {}", e))?;
+    .optional()
+    .map_err(|e| format!("Failed to load message: {}", e))
+}
 
-    // Migration: Add context_level column if it doesn't exist (for existing databases)
-    let _ = conn.execute(
-        "ALTER TABLE chat_history ADD COLUMN context_level INTEGER DEFAULT 0",
-        [],
-    ); // Ignore error if column already exists
+/// Gets a message with up to `count` messages on either side of it in its
+/// conversation, oldest first. Tool rows around it are skipped.
+pub fn get_messages_around_internal(
+    db: &Db,
+    message_id: i64,
+    count: i64,
+) -> Result<Vec<ChatMessage>, String> {
+    db.read(|conn| {
+        let neighbours = |comparison: &str, order: &str| {
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT {} FROM chat_history
+                     WHERE conversation_id IS (SELECT conversation_id FROM chat_history WHERE id = ?1)
+                       AND id {} ?1 AND role != 'tool'
+                     ORDER BY id {} LIMIT ?2",
+                    CHAT_MESSAGE_COLUMNS, comparison, order
+                ))
+                .map_err(|e| format!("Failed to prepare query: {}", e))?;
+            let rows = stmt
+                .query_map(params![message_id, count], row_to_chat_message)
+                .map_err(|e| format!("Failed to query: {}", e))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Failed to read history: {}", e))
+        };
 
-    Ok(conn)
+        let message = load_message(conn, message_id)?
+            .ok_or_else(|| format!("Message {} not found", message_id))?;
+
+        let mut messages = neighbours("<", "DESC")?;
+        messages.reverse();
+        messages.push(message);
+        messages.extend(neighbours(">", "ASC")?);
+        Ok(messages)
+    })
 }
 
-/// Stores a chat message in the database
-pub fn store_chat_message(
-    timestamp: &str,
-    role: &str,
-    content: &str,
+/// Retrieves a conversation's messages with the given roles and ids strictly
+/// between `after_id` and `before_id`, oldest first
+pub fn get_chat_messages_between(
+    db: &Db,
+    conversation_id: i64,
+    after_id: i64,
+    before_id: i64,
+    roles: &[&str],
+    limit: i64,
+) -> Result<Vec<ChatMessage>, String> {
+    db.read(|conn| {
+        let role_placeholders = vec!["?"; roles.len()].join(", ");
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM chat_history WHERE conversation_id = ? AND id > ? AND id < ? AND role IN ({}) ORDER BY id ASC LIMIT ?",
+                CHAT_MESSAGE_COLUMNS, role_placeholders
+            ))
+            .map_err(|e| format!("Failed to prepare query: {}", e))?;
+
+        let mut values: Vec<Value> =
+            vec![conversation_id.into(), after_id.into(), before_id.into()];
+        values.extend(roles.iter().map(|r| Value::from(r.to_string())));
+        values.push(limit.into());
+
+        let messages = stmt
+            .query_map(params_from_iter(values), row_to_chat_message)
+            .map_err(|e| format!("Failed to query: {}", e))?;
+
+        Ok(messages.filter_map(|m| m.ok()).collect())
+    })
+}
+
+/// Appends `AND …` conditions for the set filters on the `h` alias of
+/// chat_history, pushing their values in placeholder order
+fn history_filter_sql(filters: &HistoryFilters, values: &mut Vec<Value>) -> String {
+    let mut sql = String::new();
+    if let Some(conversation_id) = filters.conversation_id {
+        sql.push_str(" AND h.conversation_id = ?");
+        values.push(conversation_id.into());
+    }
+    match &filters.role {
+        Some(role) => {
+            sql.push_str(" AND h.role = ?");
+            values.push(role.clone().into());
+        }
+        // Tool invocations are only searched when asked for by role
+        None => sql.push_str(" AND h.role != 'tool'"),
+    }
+    if let Some(context_level) = filters.context_level {
+        sql.push_str(" AND COALESCE(h.context_level, 0) = ?");
+        values.push(i64::from(context_level).into());
+    }
+    if let Some(from) = &filters.from {
+        sql.push_str(" AND date(h.timestamp, 'localtime') >= ?");
+        values.push(from.clone().into());
+    }
+    if let Some(to) = &filters.to {
+        sql.push_str(" AND date(h.timestamp, 'localtime') <= ?");
+        values.push(to.clone().into());
+    }
+    sql
+}
+
+/// Turns free text into an FTS5 query matching messages that contain every
+/// word, the last one as a prefix so results show up while typing. Quoting
+/// each word keeps punctuation from being read as query syntax.
+fn fts_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+    Some(format!("{}*", words.join(" ")))
+}
+
+/// Marks around matched terms in FTS snippets; control characters don't
+/// occur in chat text, unlike any HTML or markdown marker
+const MATCH_START: char = '\u{1}';
+const MATCH_END: char = '\u{2}';
+
+/// Escapes a marked snippet for display as HTML, turning the match markers
+/// into `<mark>` tags
+fn highlight_html(excerpt: &str) -> String {
+    let mut html = String::with_capacity(excerpt.len() + 16);
+    for c in excerpt.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Finds messages matching a full-text query, best matches first
+pub fn search_chat_history_internal(
+    db: &Db,
+    query: &str,
+    filters: &HistoryFilters,
+    limit: i64,
+) -> Result<Vec<ChatSearchHit>, String> {
+    let Some(fts_query) = fts_query(query) else {
+        return Ok(Vec::new());
+    };
+
+    db.read(|conn| {
+        let mut values: Vec<Value> = vec![fts_query.into()];
+        let filter_sql = history_filter_sql(filters, &mut values);
+        values.push(limit.into());
+
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT h.id, h.conversation_id, h.timestamp, h.role, COALESCE(h.context_level, 0),
+                        snippet(chat_history_fts, 0, char(1), char(2), '…', 16),
+                        bm25(chat_history_fts)
+                 FROM chat_history_fts JOIN chat_history h ON h.id = chat_history_fts.rowid
+                 WHERE chat_history_fts MATCH ?{}
+                 ORDER BY bm25(chat_history_fts) LIMIT ?",
+                filter_sql
+            ))
+            .map_err(|e| format!("Failed to prepare query: {}", e))?;
+
+        let rows = stmt
+            .query_map(params_from_iter(values), |row| {
+                let excerpt: String = row.get(5)?;
+                Ok(ChatSearchHit {
+                    message_id: row.get(0)?,
+                    conversation_id: row.get(1)?,
+                    timestamp: row.get(2)?,
+                    role: row.get(3)?,
+                    context_level: row.get::<_, i64>(4)? as u8,
+                    snippet: highlight_html(&excerpt),
+                    excerpt: excerpt.replace([MATCH_START, MATCH_END], ""),
+                    rank: row.get(6)?,
+                })
+            })
+            .map_err(|e| format!("Failed to search history: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read search results: {}", e))
+    })
+}
+
+/// Gets the rolling summary for a conversation's context level, if one has been written
+pub fn get_conversation_summary(
+    db: &Db,
+    conversation_id: i64,
     context_level: u8,
+) -> Result<Option<ConversationSummary>, String> {
+    db.read(|conn| {
+        conn.query_row(
+            "SELECT conversation_id, context_level, summary, last_message_id, updated_at FROM conversation_summaries WHERE conversation_id = ?1 AND context_level = ?2",
+            params![conversation_id, context_level],
+            |row| {
+                Ok(ConversationSummary {
+                    conversation_id: row.get(0)?,
+                    context_level: row.get::<_, i64>(1)? as u8,
+                    summary: row.get(2)?,
+                    last_message_id: row.get(3)?,
+                    updated_at: row.get(4)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(|e| format!("Failed to load summary: {}", e))
+    })
+}
+
+/// Inserts or replaces the rolling summary for a conversation's context level
+pub fn save_conversation_summary(db: &Db, summary: &ConversationSummary) -> Result<(), String> {
+    db.write(|conn| {
+        conn.execute(
+            "INSERT OR REPLACE INTO conversation_summaries (conversation_id, context_level, summary, last_message_id, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                summary.conversation_id,
+                summary.context_level,
+                summary.summary,
+                summary.last_message_id,
+                summary.updated_at
+            ],
+        )
+        .map_err(|e| format!("Failed to save summary: {}", e))?;
+        Ok(())
+    })
+}
+
+/// Columns selected for every Conversation query, in the order read by `row_to_conversation`
+const CONVERSATION_COLUMNS: &str =
+    "id, title, persona, created_at, updated_at, COALESCE(archived, 0)";
+
+fn row_to_conversation(row: &Row) -> rusqlite::Result<Conversation> {
+    Ok(Conversation {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        persona: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+        archived: row.get::<_, i64>(5)? != 0,
+    })
+}
+
+/// Creates a conversation, returning it
+pub fn create_conversation_internal(
+    db: &Db,
+    title: &str,
+    persona: Option<&str>,
+    created_at: &str,
+) -> Result<Conversation, String> {
+    db.write(|conn| {
+        conn.execute(
+            "INSERT INTO conversations (title, persona, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+            params![title, persona, created_at],
+        )
+        .map_err(|e| format!("Failed to create conversation: {}", e))?;
+        load_conversation(conn, conn.last_insert_rowid())?
+            .ok_or_else(|| "Failed to create conversation".to_string())
+    })
+}
+
+/// Gets a conversation by id
+pub fn get_conversation(db: &Db, conversation_id: i64) -> Result<Option<Conversation>, String> {
+    db.read(|conn| load_conversation(conn, conversation_id))
+}
+
+fn load_conversation(
+    conn: &Connection,
+    conversation_id: i64,
+) -> Result<Option<Conversation>, String> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM conversations WHERE id = ?1",
+            CONVERSATION_COLUMNS
+        ),
+        params![conversation_id],
+        row_to_conversation,
+    )
+    .optional()
+    .map_err(|e| format!("Failed to load conversation: {}", e))
+}
+
+/// Lists conversations, most recently active first
+pub fn list_conversations_internal(
+    db: &Db,
+    include_archived: bool,
+) -> Result<Vec<Conversation>, String> {
+    db.read(|conn| {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM conversations WHERE ?1 OR COALESCE(archived, 0) = 0 ORDER BY updated_at DESC, id DESC",
+                CONVERSATION_COLUMNS
+            ))
+            .map_err(|e| format!("Failed to prepare query: {}", e))?;
+
+        let rows = stmt
+            .query_map(params![include_archived], row_to_conversation)
+            .map_err(|e| format!("Failed to query: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read conversations: {}", e))
+    })
+}
+
+/// Runs an UPDATE on one conversation, failing if it doesn't exist
+fn update_conversation(
+    db: &Db,
+    conversation_id: i64,
+    column: &str,
+    value: Value,
+) -> Result<(), String> {
+    db.write(|conn| {
+        let updated = conn
+            .execute(
+                &format!("UPDATE conversations SET {} = ?1 WHERE id = ?2", column),
+                params![value, conversation_id],
+            )
+            .map_err(|e| format!("Failed to update conversation: {}", e))?;
+        if updated == 0 {
+            return Err(format!("Conversation {} not found", conversation_id));
+        }
+        Ok(())
+    })
+}
+
+/// Renames a conversation
+pub fn rename_conversation_internal(
+    db: &Db,
+    conversation_id: i64,
+    title: &str,
+) -> Result<(), String> {
+    update_conversation(db, conversation_id, "title", title.to_string().into())
+}
+
+/// Sets or clears the persona notes of a conversation
+pub fn set_conversation_persona_internal(
+    db: &Db,
+    conversation_id: i64,
+    persona: Option<&str>,
 ) -> Result<(), String> {
-    let conn = init_database()?;
+    let value = persona.map_or(Value::Null, |p| p.to_string().into());
+    update_conversation(db, conversation_id, "persona", value)
+}
+
+/// Archives or restores a conversation
+pub fn archive_conversation_internal(
+    db: &Db,
+    conversation_id: i64,
+    archived: bool,
+) -> Result<(), String> {
+    update_conversation(db, conversation_id, "archived", (archived as i64).into())
+}
+
+/// Deletes a conversation with its messages, attachments and summaries
+pub fn delete_conversation_internal(db: &Db, conversation_id: i64) -> Result<(), String> {
+    db.write(|conn| {
+        let tx = conn
+            .unchecked_transaction()
+            .map_err(|e| format!("Failed to start delete: {}", e))?;
+        let stored_paths = delete_conversation_history(&tx, conversation_id)?;
+        let deleted = tx
+            .execute(
+                "DELETE FROM conversations WHERE id = ?1",
+                params![conversation_id],
+            )
+            .map_err(|e| format!("Failed to delete conversation: {}", e))?;
+        if deleted == 0 {
+            return Err(format!("Conversation {} not found", conversation_id));
+        }
+        tx.commit()
+            .map_err(|e| format!("Failed to delete conversation: {}", e))?;
+        remove_stored_copies(&stored_paths);
+        Ok(())
+    })
+}
+
+/// Deletes the messages, attachments and summaries of one conversation,
+/// returning the stored attachment copies to remove once committed
+fn delete_conversation_history(
+    conn: &Connection,
+    conversation_id: i64,
+) -> Result<Vec<String>, String> {
+    let stored_paths = attachment_paths(
+        conn,
+        "SELECT stored_path FROM message_attachments WHERE message_id IN (SELECT id FROM chat_history WHERE conversation_id = ?1)",
+        params![conversation_id],
+    )?;
     conn.execute(
-        "INSERT INTO chat_history (timestamp, role, content, context_level) VALUES (?1, ?2, ?3, ?4)",
-        params![timestamp, role, content, context_level],
-    ).map_err(|e| format!("Failed to store message: {}", e))?;
-    Ok(())
-}
-
-/// Retrieves chat history from the database
-pub fn get_chat_history_internal(limit: i64) -> Result<Vec<ChatMessage>, String> {
-    let conn = init_database()?;
-    let mut stmt = conn.prepare(
-        "SELECT id, timestamp, role, content, COALESCE(context_level, 0) FROM chat_history ORDER BY id DESC LIMIT ?1"
-    ).map_err(|e| format!("Failed to prepare query: {}", e))?;
-
-    let messages = stmt
-        .query_map(params![limit], |row| {
-            Ok(ChatMessage {
-                id: Some(row.get(0)?),
-                timestamp: row.get(1)?,
-                role: row.get(2)?,
-                content: row.get(3)?,
-                context_level: row.get::<_, i64>(4)? as u8,
-            })
-        })
+        "DELETE FROM message_attachments WHERE message_id IN (SELECT id FROM chat_history WHERE conversation_id = ?1)",
+        params![conversation_id],
+    )
+    .map_err(|e| format!("Failed to clear attachments: {}", e))?;
+    conn.execute(
+        "DELETE FROM chat_history WHERE conversation_id = ?1",
+        params![conversation_id],
+    )
+    .map_err(|e| format!("Failed to clear history: {}", e))?;
+    conn.execute(
+        "DELETE FROM conversation_summaries WHERE conversation_id = ?1",
+        params![conversation_id],
+    )
+    .map_err(|e| format!("Failed to clear summaries: {}", e))?;
+    Ok(stored_paths)
+}
+
+/// Collects the `stored_path` column of an attachment query
+fn attachment_paths(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare(sql)
+        .map_err(|e| format!("Failed to prepare query: {}", e))?;
+    let rows = stmt
+        .query_map(params, |row| row.get(0))
         .map_err(|e| format!("Failed to query: {}", e))?;
+    rows.collect::<Result<Vec<String>, _>>()
+        .map_err(|e| format!("Failed to read attachments: {}", e))
+}
+
+/// Links attachments to a stored message; either all rows are stored or none
+pub fn store_message_attachments(db: &Db, attachments: &[MessageAttachment]) -> Result<(), String> {
+    if attachments.is_empty() {
+        return Ok(());
+    }
+    db.write(|conn| {
+        let tx = conn
+            .unchecked_transaction()
+            .map_err(|e| format!("Failed to start storing attachments: {}", e))?;
+        for attachment in attachments {
+            tx.execute(
+                "INSERT INTO message_attachments (message_id, file_name, stored_path, mime_type, kind, size_bytes, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    attachment.message_id,
+                    attachment.file_name,
+                    attachment.stored_path,
+                    attachment.mime_type,
+                    attachment.kind,
+                    attachment.size_bytes,
+                    attachment.created_at
+                ],
+            )
+            .map_err(|e| format!("Failed to store attachment: {}", e))?;
+        }
+        tx.commit()
+            .map_err(|e| format!("Failed to store attachments: {}", e))
+    })
+}
+
+/// Gets the attachments linked to a message, in the order they were attached
+pub fn get_message_attachments_internal(
+    db: &Db,
+    message_id: i64,
+) -> Result<Vec<MessageAttachment>, String> {
+    db.read(|conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, message_id, file_name, stored_path, mime_type, kind, size_bytes, created_at
+                 FROM message_attachments WHERE message_id = ?1 ORDER BY id ASC",
+            )
+            .map_err(|e| format!("Failed to prepare query: {}", e))?;
+
+        let rows = stmt
+            .query_map(params![message_id], |row| {
+                Ok(MessageAttachment {
+                    id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    file_name: row.get(2)?,
+                    stored_path: row.get(3)?,
+                    mime_type: row.get(4)?,
+                    kind: row.get(5)?,
+                    size_bytes: row.get(6)?,
+                    created_at: row.get(7)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read attachments: {}", e))
+    })
+}
+
+/// Adds a paid call to the usage ledger, which history deletes never touch
+pub fn record_usage(
+    db: &Db,
+    timestamp: &str,
+    purpose: &str,
+    model: &str,
+    usage: &Usage,
+    latency_ms: Option<u64>,
+) -> Result<(), String> {
+    db.write(|conn| {
+        conn.execute(
+            "INSERT INTO usage_ledger (timestamp, purpose, model, prompt_tokens, completion_tokens, cost, latency_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                timestamp,
+                purpose,
+                model,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.cost,
+                latency_ms.map(|ms| ms as i64)
+            ],
+        )
+        .map_err(|e| format!("Failed to record usage: {}", e))?;
+        Ok(())
+    })
+}
+
+/// Totals ledger usage per local day and model, optionally limited to an
+/// inclusive range of YYYY-MM-DD dates
+pub fn get_usage_report_internal(
+    db: &Db,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Vec<UsageReportRow>, String> {
+    db.read(|conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT date(timestamp, 'localtime') AS day, model, COUNT(*),
+                        COALESCE(SUM(prompt_tokens), 0), COALESCE(SUM(completion_tokens), 0),
+                        COALESCE(SUM(cost), 0.0), AVG(latency_ms)
+                 FROM usage_ledger
+                 WHERE (?1 IS NULL OR day >= ?1)
+                   AND (?2 IS NULL OR day <= ?2)
+                 GROUP BY day, model
+                 ORDER BY day ASC, model ASC",
+            )
+            .map_err(|e| format!("Failed to prepare query: {}", e))?;
+
+        let rows = stmt
+            .query_map(params![from, to], |row| {
+                Ok(UsageReportRow {
+                    date: row.get(0)?,
+                    model: row.get(1)?,
+                    requests: row.get(2)?,
+                    prompt_tokens: row.get(3)?,
+                    completion_tokens: row.get(4)?,
+                    cost: row.get(5)?,
+                    avg_latency_ms: row.get(6)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query: {}", e))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read usage: {}", e))
+    })
+}
+
+/// Gets the total tokens and cost of calls made at or after `since` (RFC 3339)
+pub fn get_usage_totals_since(db: &Db, since: &str) -> Result<(u64, f64), String> {
+    db.read(|conn| {
+        conn.query_row(
+            "SELECT COALESCE(SUM(prompt_tokens + completion_tokens), 0), COALESCE(SUM(cost), 0.0)
+             FROM usage_ledger
+             WHERE julianday(timestamp) >= julianday(?1)",
+            params![since],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)),
+        )
+        .map_err(|e| format!("Failed to load usage totals: {}", e))
+    })
+}
 
-    let mut result: Vec<ChatMessage> = messages.filter_map(|m| m.ok()).collect();
+/// Clears the history of one conversation, or of every conversation when
+/// `conversation_id` is `None`; the conversations themselves are kept
+pub fn clear_chat_history_internal(db: &Db, conversation_id: Option<i64>) -> Result<(), String> {
+    db.write(|conn| {
+        if let Some(conversation_id) = conversation_id {
+            let tx = conn
+                .unchecked_transaction()
+                .map_err(|e| format!("Failed to start clear: {}", e))?;
+            let stored_paths = delete_conversation_history(&tx, conversation_id)?;
+            tx.commit()
+                .map_err(|e| format!("Failed to clear history: {}", e))?;
+            remove_stored_copies(&stored_paths);
+            return Ok(());
+        }
 
-    // Reverse to get chronological order
-    result.reverse();
-    Ok(result)
+        let stored_paths =
+            attachment_paths(conn, "SELECT stored_path FROM message_attachments", [])?;
+        conn.execute("DELETE FROM chat_history", [])
+            .map_err(|e| format!("Failed to clear history: {}", e))?;
+        conn.execute("DELETE FROM conversation_summaries", [])
+            .map_err(|e| format!("Failed to clear summaries: {}", e))?;
+        conn.execute("DELETE FROM message_attachments", [])
+            .map_err(|e| format!("Failed to clear attachments: {}", e))?;
+        remove_stored_copies(&stored_paths);
+        Ok(())
+    })
 }
 
-/// Clears all chat history from the database
-pub fn clear_chat_history_internal() -> Result<(), String> {
-    let conn = init_database()?;
-    conn.execute("DELETE FROM chat_history", [])
-        .map_err(|e| format!("Failed to clear history: {}", e))?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fts_query_quotes_words_and_prefixes_the_last() {
+        assert_eq!(
+            fts_query("cargo clip"),
+            Some("\"cargo\" \"clip\"*".to_string())
+        );
+        assert_eq!(
+            fts_query("  spaced\tout\n"),
+            Some("\"spaced\" \"out\"*".to_string())
+        );
+        assert_eq!(fts_query("   "), None);
+    }
+
+    #[test]
+    fn fts_query_neutralises_query_syntax() {
+        assert_eq!(
+            fts_query("a OR b-c"),
+            Some("\"a\" \"OR\" \"b-c\"*".to_string())
+        );
+        assert_eq!(
+            fts_query("say \"hi\" NEAR(x"),
+            Some("\"say\" \"\"\"hi\"\"\" \"NEAR(x\"*".to_string())
+        );
+    }
+
+    #[test]
+    fn fts_query_matches_prefixes_and_punctuation() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE t USING fts5(content);
+             INSERT INTO t VALUES ('run cargo clippy --all-targets'), ('say \"hi\" OR bye');",
+        )
+        .unwrap();
+        let matches = |text: &str| -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM t WHERE t MATCH ?1",
+                params![fts_query(text).unwrap()],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(matches("cargo clip"), 1);
+        assert_eq!(matches("clippy cargo"), 1);
+        assert_eq!(matches("--all-tar"), 1);
+        assert_eq!(matches("\"hi\" OR"), 1);
+        assert_eq!(matches("cargo bye"), 0);
+    }
+
+    #[test]
+    fn highlights_escaped_snippets() {
+        let excerpt = "\u{1}<script>\u{2} & \"x\" 'y'";
+        assert_eq!(
+            highlight_html(excerpt),
+            "<mark>&lt;script&gt;</mark> &amp; &quot;x&quot; &#39;y&#39;"
+        );
+    }
 }
@@ -0,0 +1,290 @@
+//! Emotion markers in character replies
+//!
+//! The roleplay model is asked to tag its replies with markers such as
+//! `[happy]`. The markers are stripped from the text before it is shown or
+//! stored and mapped to the expressions and motion groups declared in the
+//! current Live2D model's `.model3.json`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Emotions the model may use, in the order they are offered in the prompt
+pub const EMOTIONS: &[&str] = &[
+    "neutral",
+    "happy",
+    "excited",
+    "sad",
+    "angry",
+    "surprised",
+    "embarrassed",
+    "pout",
+    "thinking",
+    "smug",
+];
+
+/// Longest marker recognised, brackets included
+const MAX_MARKER_LEN: usize = 24;
+
+/// Name fragments that suggest an expression or motion group fits an emotion
+const EMOTION_KEYWORDS: &[(&str, &[&str])] = &[
+    ("neutral", &["neutral", "normal", "default", "idle"]),
+    ("happy", &["happy", "smile", "joy", "glad"]),
+    ("excited", &["excite", "star", "yay", "cheer"]),
+    ("sad", &["sad", "cry", "tear", "sorrow"]),
+    ("angry", &["angry", "anger", "mad", "annoy"]),
+    ("surprised", &["surprise", "shock", "startle"]),
+    ("embarrassed", &["embarrass", "blush", "shy"]),
+    ("pout", &["pout", "sulk", "hmph"]),
+    ("thinking", &["think", "ponder", "confus", "question"]),
+    ("smug", &["smug", "grin", "wink", "tease"]),
+];
+
+/// Instructions appended to the roleplay system prompt
+pub fn prompt_addendum() -> String {
+    format!(
+        "Express your feelings with emotion markers in square brackets, e.g. [happy]. \
+         Put one at the start of your reply and another wherever your mood changes. \
+         Use only these: {}. The markers are hidden from the user and drive your avatar's face.",
+        EMOTIONS
+            .iter()
+            .map(|e| format!("[{}]", e))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+fn as_emotion(marker: &str) -> Option<&'static str> {
+    let marker = marker.trim().to_lowercase();
+    EMOTIONS.iter().copied().find(|e| *e == marker)
+}
+
+/// Removes emotion markers from streamed text, holding back a possible
+/// marker that is split across chunks until it can be decided
+#[derive(Debug, Default)]
+pub struct EmotionStripper {
+    pending: String,
+    /// Drop the space after a marker that started the text or followed
+    /// whitespace, so removing it doesn't leave a double space
+    skip_space: bool,
+    after_whitespace: bool,
+    started: bool,
+}
+
+impl EmotionStripper {
+    /// Feeds a chunk, returning the text to show and the emotions it named
+    pub fn push(&mut self, chunk: &str) -> (String, Vec<&'static str>) {
+        self.pending.push_str(chunk);
+        let buffer = std::mem::take(&mut self.pending);
+        let mut text = String::with_capacity(buffer.len());
+        let mut emotions = Vec::new();
+        let mut rest = buffer.as_str();
+
+        loop {
+            if self.skip_space && !rest.is_empty() {
+                rest = rest.strip_prefix(' ').unwrap_or(rest);
+                self.skip_space = false;
+            }
+
+            let Some(open) = rest.find('[') else {
+                self.emit(&mut text, rest);
+                break;
+            };
+            self.emit(&mut text, &rest[..open]);
+            let candidate = &rest[open..];
+
+            match candidate.find(']') {
+                Some(close) if close < MAX_MARKER_LEN => {
+                    if let Some(emotion) = as_emotion(&candidate[1..close]) {
+                        emotions.push(emotion);
+                        self.skip_space = !self.started || self.after_whitespace;
+                    } else {
+                        self.emit(&mut text, &candidate[..close + 1]);
+                    }
+                    rest = &candidate[close + 1..];
+                }
+                // Could still become a marker once more text arrives
+                None if candidate.len() < MAX_MARKER_LEN && !candidate.contains('\n') => {
+                    self.pending = candidate.to_string();
+                    break;
+                }
+                _ => {
+                    self.emit(&mut text, "[");
+                    rest = &candidate[1..];
+                }
+            }
+        }
+
+        (text, emotions)
+    }
+
+    /// Returns text held back at the end of the stream
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    fn emit(&mut self, text: &mut String, chunk: &str) {
+        if let Some(last) = chunk.chars().last() {
+            text.push_str(chunk);
+            self.started = true;
+            self.after_whitespace = last.is_whitespace();
+        }
+    }
+}
+
+/// Removes emotion markers from a complete reply
+pub fn strip_emotions(text: &str) -> (String, Vec<&'static str>) {
+    let mut stripper = EmotionStripper::default();
+    let (mut stripped, emotions) = stripper.push(text);
+    stripped.push_str(&stripper.finish());
+    (stripped, emotions)
+}
+
+/// What the avatar does for one emotion
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EmotionAction {
+    /// Expression name from the model's `Expressions`
+    pub expression: Option<String>,
+    /// Motion group from the model's `Motions`; a random motion in it plays
+    pub motion: Option<String>,
+}
+
+/// Emotion name to avatar action, saved per Live2D model
+pub type EmotionMap = HashMap<String, EmotionAction>;
+
+/// Expressions and motion groups a Live2D model declares
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ModelEmotionAssets {
+    pub expressions: Vec<String>,
+    pub motion_groups: Vec<String>,
+}
+
+/// Reads the expression names and motion groups from a `.model3.json`
+pub fn read_model_assets(model_file: &Path) -> Result<ModelEmotionAssets, String> {
+    let content = std::fs::read_to_string(model_file)
+        .map_err(|e| format!("Failed to read model file: {}", e))?;
+    let json: Value =
+        serde_json::from_str(&content).map_err(|e| format!("Failed to parse model file: {}", e))?;
+    let references = &json["FileReferences"];
+
+    let expressions = references["Expressions"]
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .filter_map(|e| e["Name"].as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut motion_groups: Vec<String> = references["Motions"]
+        .as_object()
+        .map(|groups| groups.keys().cloned().collect())
+        .unwrap_or_default();
+    motion_groups.sort();
+
+    Ok(ModelEmotionAssets {
+        expressions,
+        motion_groups,
+    })
+}
+
+/// Guesses a mapping by matching emotion keywords against asset names
+pub fn default_mapping(assets: &ModelEmotionAssets) -> EmotionMap {
+    let find = |names: &[String], keywords: &[&str]| {
+        names
+            .iter()
+            .find(|name| {
+                let name = name.to_lowercase();
+                keywords.iter().any(|k| name.contains(k))
+            })
+            .cloned()
+    };
+
+    EMOTION_KEYWORDS
+        .iter()
+        .filter_map(|(emotion, keywords)| {
+            let action = EmotionAction {
+                expression: find(&assets.expressions, keywords),
+                // Idle motions already loop on their own
+                motion: find(&assets.motion_groups, keywords)
+                    .filter(|group| !group.eq_ignore_ascii_case("idle")),
+            };
+            (action != EmotionAction::default()).then(|| (emotion.to_string(), action))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds chunks through a stripper, returning the shown text and emotions
+    fn strip_chunks(chunks: &[&str]) -> (String, Vec<&'static str>) {
+        let mut stripper = EmotionStripper::default();
+        let mut text = String::new();
+        let mut emotions = Vec::new();
+        for chunk in chunks {
+            let (shown, found) = stripper.push(chunk);
+            text.push_str(&shown);
+            emotions.extend(found);
+        }
+        text.push_str(&stripper.finish());
+        (text, emotions)
+    }
+
+    #[test]
+    fn strips_leading_marker() {
+        assert_eq!(
+            strip_emotions("[happy] Hi!"),
+            ("Hi!".to_string(), vec!["happy"])
+        );
+    }
+
+    #[test]
+    fn strips_marker_split_across_chunks() {
+        assert_eq!(
+            strip_chunks(&["[ha", "ppy] hi"]),
+            ("hi".to_string(), vec!["happy"])
+        );
+        assert_eq!(
+            strip_chunks(&["Well", " [", "SAD", "]", " oh"]),
+            ("Well oh".to_string(), vec!["sad"])
+        );
+    }
+
+    #[test]
+    fn keeps_brackets_that_are_not_emotions() {
+        assert_eq!(strip_emotions("a [x] b").0, "a [x] b");
+        assert_eq!(
+            strip_emotions("see [the docs](https://example.com)").0,
+            "see [the docs](https://example.com)"
+        );
+        let (text, emotions) = strip_chunks(&["[not an emotion at all, clearly", "] ok"]);
+        assert_eq!(text, "[not an emotion at all, clearly] ok");
+        assert!(emotions.is_empty());
+    }
+
+    #[test]
+    fn finish_flushes_held_text() {
+        let mut stripper = EmotionStripper::default();
+        assert_eq!(stripper.push("Hello [hap").0, "Hello ");
+        assert_eq!(stripper.finish(), "[hap");
+        assert_eq!(stripper.finish(), "");
+    }
+
+    #[test]
+    fn newline_ends_a_candidate_marker() {
+        assert_eq!(strip_chunks(&["[hap", "\npy] no"]).0, "[hap\npy] no");
+        assert_eq!(
+            strip_emotions("Hi.\n[sad] Bye.\n[happy]Ok"),
+            ("Hi.\nBye.\nOk".to_string(), vec!["sad", "happy"])
+        );
+    }
+
+    #[test]
+    fn mid_sentence_marker_leaves_single_space() {
+        assert_eq!(strip_emotions("I'm [happy] glad").0, "I'm glad");
+        assert_eq!(strip_chunks(&["I'm [happy]", " glad"]).0, "I'm glad");
+        assert_eq!(strip_emotions("I'm[happy] glad").0, "I'm glad");
+    }
+}
@@ -0,0 +1,306 @@
+//! Spending limits checked before each LLM request, and a request rate cap
+//! checked for each message the user sends
+//!
+//! Token and cost totals come from the usage ledger, which records every
+//! paid call, so a cap stops the next request once the calls so far have
+//! reached it. The rate cap counts user messages rather than calls, since
+//! one message can fan out into tool rounds, a vision relay call, a summary
+//! and commentary.
+
+use crate::db::{get_usage_totals_since, Db};
+use chrono::{Datelike, Local, NaiveDate, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Prefix of the error returned when a limit stops a request, so the
+/// frontend can tell it apart from provider failures
+pub const LIMIT_ERROR_PREFIX: &str = "LIMIT_REACHED:";
+
+/// Caps on token use, spend and request rate; `None` means unlimited
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SpendingLimits {
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+    /// Daily spend in USD, counted from provider-reported costs
+    pub daily_cost: Option<f64>,
+    /// Monthly spend in USD, counted from provider-reported costs
+    pub monthly_cost: Option<f64>,
+    pub requests_per_minute: Option<u32>,
+}
+
+/// Limits that always apply to requests paid for by the built-in API key
+pub const BUILTIN_KEY_LIMITS: SpendingLimits = SpendingLimits {
+    daily_tokens: Some(200_000),
+    monthly_tokens: Some(2_000_000),
+    daily_cost: Some(0.50),
+    monthly_cost: Some(5.00),
+    requests_per_minute: Some(10),
+};
+
+/// How far back the per-minute cap looks
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Send times of the user messages counted in the last minute
+static RECENT_REQUESTS: Mutex<VecDeque<Instant>> = Mutex::new(VecDeque::new());
+
+impl SpendingLimits {
+    /// Combines two sets of limits, keeping the stricter value of each
+    pub fn stricter(&self, other: &SpendingLimits) -> SpendingLimits {
+        fn min<T: PartialOrd + Copy>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(if b < a { b } else { a }),
+                (a, b) => a.or(b),
+            }
+        }
+
+        SpendingLimits {
+            daily_tokens: min(self.daily_tokens, other.daily_tokens),
+            monthly_tokens: min(self.monthly_tokens, other.monthly_tokens),
+            daily_cost: min(self.daily_cost, other.daily_cost),
+            monthly_cost: min(self.monthly_cost, other.monthly_cost),
+            requests_per_minute: min(self.requests_per_minute, other.requests_per_minute),
+        }
+    }
+
+    /// Rejects caps that could never be met or never trip: zero, negative
+    /// and non-finite values. Leave a cap unset for no limit.
+    pub fn validate(&self) -> Result<(), String> {
+        let caps = [
+            ("daily_tokens", self.daily_tokens.map(|t| t as f64)),
+            ("monthly_tokens", self.monthly_tokens.map(|t| t as f64)),
+            ("daily_cost", self.daily_cost),
+            ("monthly_cost", self.monthly_cost),
+            (
+                "requests_per_minute",
+                self.requests_per_minute.map(f64::from),
+            ),
+        ];
+        for (name, cap) in caps {
+            match cap {
+                Some(cap) if !(cap.is_finite() && cap > 0.0) => {
+                    return Err(format!(
+                        "Invalid {}: {} (must be greater than 0)",
+                        name, cap
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the token and spending caps before a request is sent. Fails with
+/// a `LIMIT_ERROR_PREFIX` error when a cap is hit.
+pub fn check_limits(db: &Db, limits: &SpendingLimits) -> Result<(), String> {
+    let today = Local::now().date_naive();
+
+    if limits.daily_tokens.is_some() || limits.daily_cost.is_some() {
+        let (tokens, cost) = get_usage_totals_since(db, &local_midnight_utc(today))?;
+        check_cap(
+            "daily token",
+            tokens as f64,
+            limits.daily_tokens.map(|t| t as f64),
+        )?;
+        check_cap("daily spending", cost, limits.daily_cost)?;
+    }
+
+    if limits.monthly_tokens.is_some() || limits.monthly_cost.is_some() {
+        let month_start = today.with_day(1).unwrap_or(today);
+        let (tokens, cost) = get_usage_totals_since(db, &local_midnight_utc(month_start))?;
+        check_cap(
+            "monthly token",
+            tokens as f64,
+            limits.monthly_tokens.map(|t| t as f64),
+        )?;
+        check_cap("monthly spending", cost, limits.monthly_cost)?;
+    }
+
+    Ok(())
+}
+
+/// Counts a user message toward the per-minute cap, failing with a
+/// `LIMIT_ERROR_PREFIX` error when the cap is reached. Messages sent without
+/// a cap, e.g. to a local model, aren't counted.
+pub fn count_request(limits: &SpendingLimits) -> Result<(), String> {
+    let Some(cap) = limits.requests_per_minute else {
+        return Ok(());
+    };
+    let mut recent = RECENT_REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+    admit_request(&mut recent, Instant::now(), cap)
+}
+
+/// Forgets requests that left the window, then records one sent at `now`
+/// unless `cap` requests are still in it
+fn admit_request(recent: &mut VecDeque<Instant>, now: Instant, cap: u32) -> Result<(), String> {
+    while recent
+        .front()
+        .is_some_and(|sent| now.duration_since(*sent) >= RATE_WINDOW)
+    {
+        recent.pop_front();
+    }
+    if recent.len() >= cap as usize {
+        return Err(format!(
+            "{} Rate limit of {} requests per minute reached",
+            LIMIT_ERROR_PREFIX, cap
+        ));
+    }
+    recent.push_back(now);
+    Ok(())
+}
+
+fn check_cap(name: &str, used: f64, cap: Option<f64>) -> Result<(), String> {
+    match cap {
+        Some(cap) if used >= cap => Err(format!(
+            "{} The {} limit has been reached",
+            LIMIT_ERROR_PREFIX, name
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Start of a local calendar day as an RFC 3339 UTC timestamp
+fn local_midnight_utc(date: NaiveDate) -> String {
+    let midnight = date.and_time(NaiveTime::MIN);
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|local| local.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&midnight))
+        .to_rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::record_usage;
+    use crate::models::Usage;
+
+    #[test]
+    fn unset_user_caps_keep_the_builtin_limits() {
+        let user = SpendingLimits {
+            daily_tokens: Some(1_000),
+            daily_cost: Some(10.0),
+            ..Default::default()
+        };
+        let limits = user.stricter(&BUILTIN_KEY_LIMITS);
+        assert_eq!(limits.daily_tokens, Some(1_000));
+        assert_eq!(limits.daily_cost, BUILTIN_KEY_LIMITS.daily_cost);
+        assert_eq!(limits.monthly_tokens, BUILTIN_KEY_LIMITS.monthly_tokens);
+        assert_eq!(limits.monthly_cost, BUILTIN_KEY_LIMITS.monthly_cost);
+        assert_eq!(
+            limits.requests_per_minute,
+            BUILTIN_KEY_LIMITS.requests_per_minute
+        );
+
+        assert_eq!(
+            SpendingLimits::default().stricter(&BUILTIN_KEY_LIMITS),
+            BUILTIN_KEY_LIMITS
+        );
+    }
+
+    #[test]
+    fn rejects_caps_that_never_trip_or_always_do() {
+        assert!(SpendingLimits::default().validate().is_ok());
+        assert!(BUILTIN_KEY_LIMITS.validate().is_ok());
+        for limits in [
+            SpendingLimits {
+                daily_cost: Some(f64::NAN),
+                ..Default::default()
+            },
+            SpendingLimits {
+                monthly_cost: Some(f64::INFINITY),
+                ..Default::default()
+            },
+            SpendingLimits {
+                daily_cost: Some(-1.0),
+                ..Default::default()
+            },
+            SpendingLimits {
+                requests_per_minute: Some(0),
+                ..Default::default()
+            },
+            SpendingLimits {
+                daily_tokens: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(limits.validate().is_err(), "{:?}", limits);
+        }
+    }
+
+    #[test]
+    fn cap_is_reached_at_the_boundary() {
+        assert!(check_cap("daily token", 99.0, Some(100.0)).is_ok());
+        let error = check_cap("daily token", 100.0, Some(100.0)).unwrap_err();
+        assert!(error.starts_with(LIMIT_ERROR_PREFIX));
+        assert!(check_cap("daily token", 101.0, Some(100.0)).is_err());
+        assert!(check_cap("daily token", 1e9, None).is_ok());
+    }
+
+    #[test]
+    fn rate_cap_counts_the_last_minute() {
+        let start = Instant::now();
+        let mut recent = VecDeque::new();
+        assert!(admit_request(&mut recent, start, 2).is_ok());
+        assert!(admit_request(&mut recent, start + Duration::from_secs(1), 2).is_ok());
+
+        let error = admit_request(&mut recent, start + Duration::from_secs(59), 2).unwrap_err();
+        assert!(error.starts_with(LIMIT_ERROR_PREFIX));
+        assert_eq!(recent.len(), 2);
+
+        // The first request leaves the window after a minute
+        assert!(admit_request(&mut recent, start + RATE_WINDOW, 2).is_ok());
+        assert_eq!(recent.len(), 2);
+    }
+
+    #[test]
+    fn day_and_month_start_at_local_midnight() {
+        for (year, month, day) in [(2024, 1, 1), (2024, 2, 29), (2024, 3, 1), (2024, 12, 31)] {
+            let date = NaiveDate::from_ymd_opt(year, month, day).unwrap();
+            let start = chrono::DateTime::parse_from_rfc3339(&local_midnight_utc(date))
+                .unwrap()
+                .with_timezone(&Local);
+            assert_eq!(start.date_naive(), date);
+            assert_eq!(start.time(), NaiveTime::MIN);
+        }
+
+        let end_of_february = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        let month_start = end_of_february.with_day(1).unwrap();
+        assert_eq!(month_start, NaiveDate::from_ymd_opt(2024, 2, 1).unwrap());
+        assert!(local_midnight_utc(month_start) < local_midnight_utc(end_of_february));
+    }
+
+    #[test]
+    fn daily_cap_stops_requests_once_the_ledger_reaches_it() {
+        let dir = std::env::temp_dir().join(format!("limits_ledger_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Db::at(dir.join("chat_history.db"));
+        let limits = SpendingLimits {
+            daily_tokens: Some(1_000),
+            ..Default::default()
+        };
+        let usage = |tokens| Usage {
+            prompt_tokens: tokens,
+            completion_tokens: 0,
+            cost: None,
+        };
+
+        // Usage from before today doesn't count toward the daily cap
+        let last_month = (Utc::now() - chrono::Duration::days(40)).to_rfc3339();
+        record_usage(&db, &last_month, "reply", "m", &usage(5_000), None).unwrap();
+        assert!(check_limits(&db, &limits).is_ok());
+
+        let now = Utc::now().to_rfc3339();
+        record_usage(&db, &now, "reply", "m", &usage(600), None).unwrap();
+        assert!(check_limits(&db, &limits).is_ok());
+        record_usage(&db, &now, "summary", "m", &usage(600), None).unwrap();
+        let error = check_limits(&db, &limits).unwrap_err();
+        assert!(error.starts_with(LIMIT_ERROR_PREFIX), "{}", error);
+
+        db.close();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
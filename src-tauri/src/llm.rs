@@ -0,0 +1,922 @@
+//! LLM provider backends and request/response normalization
+//!
+//! Chat messages are always built in the OpenAI chat-completions format and
+//! translated here for providers that speak a different wire format.
+
+use crate::models::Usage;
+use crate::streaming::FinishReason;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Base URL for the OpenRouter API
+pub const OPENROUTER_BASE_URL: &str = "https://openrouter.ai/api/v1";
+
+/// Base URL for the OpenAI API
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Base URL for the Anthropic API
+pub const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+
+/// Default root URL of a local Ollama server
+pub const DEFAULT_LOCAL_BASE_URL: &str = "http://localhost:11434";
+
+/// Anthropic API version sent with every request
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Attribution headers sent to OpenRouter
+const OPENROUTER_REFERER: &str = "https://oto.frisson.app";
+const OPENROUTER_TITLE: &str = "Oto Desktop";
+
+/// System prompts at least this long get a prompt-caching breakpoint; shorter
+/// ones fall under the providers' minimum cacheable size (about 1024 tokens)
+const CACHEABLE_SYSTEM_PROMPT_CHARS: usize = 4000;
+
+/// The backend a context level sends its chat requests to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProviderKind {
+    #[default]
+    #[serde(rename = "openrouter")]
+    OpenRouter,
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// Any server implementing the OpenAI chat-completions API at a custom base URL
+    #[serde(rename = "openai_compatible")]
+    OpenAICompatible,
+    #[serde(rename = "anthropic")]
+    Anthropic,
+    /// A local OpenAI-compatible server (Ollama, llama.cpp) that needs no API key
+    #[serde(rename = "local")]
+    Local,
+}
+
+/// How hard a reasoning model should think before answering
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        }
+    }
+
+    /// Extended thinking budget used for Anthropic, which takes tokens instead of a level
+    fn anthropic_budget_tokens(&self) -> u32 {
+        match self {
+            ReasoningEffort::Low => 1024,
+            ReasoningEffort::Medium => 4096,
+            ReasoningEffort::High => 16384,
+        }
+    }
+}
+
+/// Sampling settings passed through to the provider; unset fields use the model's defaults
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SamplingSettings {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Ignored by Anthropic
+    pub presence_penalty: Option<f32>,
+    /// Ignored by Anthropic
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Ignored by Anthropic
+    pub seed: Option<u64>,
+}
+
+/// Reply length and sampling settings for one context level
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct GenerationSettings {
+    pub max_tokens: Option<u32>,
+    #[serde(flatten)]
+    pub sampling: SamplingSettings,
+}
+
+impl GenerationSettings {
+    /// Rejects sampling values outside the ranges providers accept
+    pub fn validate(&self) -> Result<(), String> {
+        let sampling = &self.sampling;
+        check_range("temperature", sampling.temperature, 0.0, 2.0)?;
+        check_range("top_p", sampling.top_p, 0.0, 1.0)?;
+        check_range("presence_penalty", sampling.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", sampling.frequency_penalty, -2.0, 2.0)
+    }
+}
+
+fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> Result<(), String> {
+    match value {
+        Some(v) if !(min..=max).contains(&v) => Err(format!(
+            "Invalid {}: {} (must be between {} and {})",
+            name, v, min, max
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// OpenRouter routing options sent with every OpenRouter request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct OpenRouterRouting {
+    /// Upstream providers to try first, in order (e.g. "anthropic", "together")
+    pub order: Vec<String>,
+    /// Whether providers outside `order` may serve the request; `None` keeps OpenRouter's default
+    pub allow_fallbacks: Option<bool>,
+    /// Only use providers that don't store or train on prompts; on unless
+    /// the user opts out, since prompts can carry screenshots of their screen
+    pub deny_data_collection: bool,
+    /// Upstream providers never to use
+    pub ignore: Vec<String>,
+    /// Prompt transforms, e.g. "middle-out" to squeeze prompts that are too long
+    pub transforms: Vec<String>,
+    /// Let OpenRouter try a level's fallback models itself, within one request
+    pub server_fallbacks: bool,
+    /// Mark long system prompts as cacheable for models that need explicit breakpoints
+    pub cache_system_prompt: bool,
+}
+
+impl Default for OpenRouterRouting {
+    fn default() -> Self {
+        Self {
+            order: Vec::new(),
+            allow_fallbacks: None,
+            deny_data_collection: true,
+            ignore: Vec::new(),
+            transforms: Vec::new(),
+            server_fallbacks: false,
+            cache_system_prompt: false,
+        }
+    }
+}
+
+impl OpenRouterRouting {
+    /// The `provider` preferences object, or `None` when nothing is set
+    fn provider_preferences(&self) -> Option<Value> {
+        let mut preferences = serde_json::Map::new();
+        if !self.order.is_empty() {
+            preferences.insert("order".into(), json!(self.order));
+        }
+        if let Some(allow) = self.allow_fallbacks {
+            preferences.insert("allow_fallbacks".into(), json!(allow));
+        }
+        if self.deny_data_collection {
+            preferences.insert("data_collection".into(), json!("deny"));
+        }
+        if !self.ignore.is_empty() {
+            preferences.insert("ignore".into(), json!(self.ignore));
+        }
+        (!preferences.is_empty()).then_some(Value::Object(preferences))
+    }
+}
+
+/// A provider resolved from the LLM config, ready to send requests
+#[derive(Clone, Debug)]
+pub struct LlmProvider {
+    pub kind: ProviderKind,
+    pub base_url: String,
+    pub api_key: Option<String>,
+    /// Only sent to OpenRouter
+    pub routing: OpenRouterRouting,
+}
+
+/// A chat request in provider-neutral form
+#[derive(Clone, Debug)]
+pub struct ChatRequest {
+    pub model: String,
+    /// Messages in OpenAI chat-completions format
+    pub messages: Vec<Value>,
+    pub max_tokens: u32,
+    pub stream: bool,
+    /// Tools advertised to the model; empty disables tool calling
+    pub tools: Vec<ToolDefinition>,
+    /// Keep `tools` advertised, as the tool calls in `messages` need them,
+    /// but have the model answer in text
+    pub forbid_tool_calls: bool,
+    /// Reasoning effort to request; `None` leaves it to the model
+    pub reasoning_effort: Option<ReasoningEffort>,
+    pub sampling: SamplingSettings,
+    /// Models OpenRouter may switch to when `model` fails; ignored by other providers
+    pub fallback_models: Vec<String>,
+}
+
+/// A tool advertised to the model
+#[derive(Clone, Debug, Serialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments object
+    pub parameters: Value,
+}
+
+/// A tool invocation requested by the model
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// Raw JSON arguments string as produced by the model
+    pub arguments: String,
+}
+
+/// A complete (non-streamed) response normalized across providers
+#[derive(Clone, Debug, Default)]
+pub struct Completion {
+    pub content: String,
+    /// Reasoning the model produced before its answer, kept apart from the content
+    pub reasoning: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub usage: Option<Usage>,
+    pub finish_reason: Option<FinishReason>,
+    /// Model the provider says served the request
+    pub model: Option<String>,
+}
+
+/// One piece of a streamed response normalized across providers
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamDelta {
+    Text(String),
+    /// Reasoning ("thinking") text, streamed separately from the answer
+    Reasoning(String),
+    /// A fragment of a tool call; id and name arrive once, arguments in pieces
+    ToolCall {
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: String,
+    },
+    /// Token usage; some providers report it in parts that should be merged
+    Usage(Usage),
+    /// Why the model stopped generating
+    Finish(FinishReason),
+    /// Model the provider says is serving the stream
+    Model(String),
+}
+
+/// Reassembles streamed tool call fragments into complete calls
+#[derive(Debug, Default)]
+pub struct ToolCallAccumulator {
+    calls: BTreeMap<usize, ToolCall>,
+}
+
+impl ToolCallAccumulator {
+    pub fn push(
+        &mut self,
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: &str,
+    ) {
+        let call = self.calls.entry(index).or_default();
+        if let Some(id) = id {
+            call.id = id;
+        }
+        if let Some(name) = name {
+            call.name.push_str(&name);
+        }
+        call.arguments.push_str(arguments);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Returns the completed calls in the order the model emitted them
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.calls.into_values().collect()
+    }
+}
+
+impl LlmProvider {
+    /// Gets the chat endpoint URL for this provider
+    pub fn chat_url(&self) -> String {
+        let base = self.base_url.trim_end_matches('/');
+        match self.kind {
+            ProviderKind::Anthropic => format!("{}/messages", base),
+            _ => format!("{}/chat/completions", base),
+        }
+    }
+
+    /// Builds the provider-specific JSON body for a chat request
+    pub fn build_body(&self, request: &ChatRequest) -> Value {
+        match self.kind {
+            ProviderKind::Anthropic => {
+                let (system, messages) = to_anthropic_messages(&request.messages);
+                let mut body = json!({
+                    "model": request.model,
+                    "messages": messages,
+                    "max_tokens": request.max_tokens
+                });
+                if !system.is_empty() {
+                    body["system"] = json!(system);
+                }
+                if request.stream {
+                    body["stream"] = json!(true);
+                }
+                // Follow-up rounds of a tool loop would have to echo the signed
+                // thinking blocks back, so thinking is only requested up front
+                let continues_tool_loop = request.messages.iter().any(|m| m["role"] == "tool");
+                let thinking = request.reasoning_effort.filter(|_| !continues_tool_loop);
+                if let Some(effort) = thinking {
+                    let budget = effort.anthropic_budget_tokens();
+                    body["thinking"] = json!({ "type": "enabled", "budget_tokens": budget });
+                    // The thinking budget counts toward max_tokens
+                    body["max_tokens"] = json!(request.max_tokens + budget);
+                } else {
+                    // Extended thinking doesn't accept custom sampling
+                    let sampling = &request.sampling;
+                    if let Some(temperature) = sampling.temperature {
+                        body["temperature"] = json!(temperature);
+                    }
+                    if let Some(top_p) = sampling.top_p {
+                        body["top_p"] = json!(top_p);
+                    }
+                }
+                if !request.sampling.stop.is_empty() {
+                    body["stop_sequences"] = json!(request.sampling.stop);
+                }
+                if !request.tools.is_empty() {
+                    body["tools"] = request
+                        .tools
+                        .iter()
+                        .map(|t| {
+                            json!({
+                                "name": t.name,
+                                "description": t.description,
+                                "input_schema": t.parameters
+                            })
+                        })
+                        .collect();
+                    if request.forbid_tool_calls {
+                        body["tool_choice"] = json!({ "type": "none" });
+                    }
+                }
+                body
+            }
+            _ => {
+                let mut body = json!({
+                    "model": request.model,
+                    "messages": request.messages,
+                    "max_tokens": request.max_tokens
+                });
+                if request.stream {
+                    body["stream"] = json!(true);
+                }
+                // Ask for token usage; OpenRouter also reports the cost
+                if self.kind == ProviderKind::OpenRouter {
+                    body["usage"] = json!({ "include": true });
+                } else if request.stream {
+                    body["stream_options"] = json!({ "include_usage": true });
+                }
+                let sampling = &request.sampling;
+                let optional_fields = [
+                    ("temperature", sampling.temperature.map(|v| json!(v))),
+                    ("top_p", sampling.top_p.map(|v| json!(v))),
+                    (
+                        "presence_penalty",
+                        sampling.presence_penalty.map(|v| json!(v)),
+                    ),
+                    (
+                        "frequency_penalty",
+                        sampling.frequency_penalty.map(|v| json!(v)),
+                    ),
+                    ("seed", sampling.seed.map(|v| json!(v))),
+                ];
+                for (field, value) in optional_fields {
+                    if let Some(value) = value {
+                        body[field] = value;
+                    }
+                }
+                if !sampling.stop.is_empty() {
+                    body["stop"] = json!(sampling.stop);
+                }
+                if let Some(effort) = request.reasoning_effort {
+                    match self.kind {
+                        ProviderKind::OpenRouter => {
+                            body["reasoning"] = json!({ "effort": effort.as_str() })
+                        }
+                        _ => body["reasoning_effort"] = json!(effort.as_str()),
+                    }
+                }
+                if !request.tools.is_empty() {
+                    body["tools"] = request
+                        .tools
+                        .iter()
+                        .map(|t| {
+                            json!({
+                                "type": "function",
+                                "function": {
+                                    "name": t.name,
+                                    "description": t.description,
+                                    "parameters": t.parameters
+                                }
+                            })
+                        })
+                        .collect();
+                    if request.forbid_tool_calls {
+                        body["tool_choice"] = json!("none");
+                    }
+                }
+                if self.kind == ProviderKind::OpenRouter {
+                    self.apply_openrouter_routing(&mut body, request);
+                }
+                body
+            }
+        }
+    }
+
+    /// Adds the OpenRouter-only routing fields to a request body
+    fn apply_openrouter_routing(&self, body: &mut Value, request: &ChatRequest) {
+        let routing = &self.routing;
+        if let Some(preferences) = routing.provider_preferences() {
+            body["provider"] = preferences;
+        }
+        if !request.fallback_models.is_empty() {
+            let mut models = vec![request.model.clone()];
+            models.extend(request.fallback_models.iter().cloned());
+            body["models"] = json!(models);
+        }
+        if !routing.transforms.is_empty() {
+            body["transforms"] = json!(routing.transforms);
+        }
+
+        // Anthropic and Gemini models only cache up to an explicit breakpoint;
+        // other providers cache long prompts on their own
+        let needs_breakpoint =
+            request.model.starts_with("anthropic/") || request.model.starts_with("google/");
+        if routing.cache_system_prompt && needs_breakpoint {
+            if let Some(messages) = body["messages"].as_array_mut() {
+                for message in messages.iter_mut().filter(|m| m["role"] == "system") {
+                    let Some(text) = message["content"].as_str() else {
+                        continue;
+                    };
+                    if text.len() >= CACHEABLE_SYSTEM_PROMPT_CHARS {
+                        message["content"] = json!([{
+                            "type": "text",
+                            "text": text,
+                            "cache_control": { "type": "ephemeral" }
+                        }]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Creates a POST request to the chat endpoint with provider auth headers
+    pub fn post_chat(&self, client: &reqwest::Client, body: &Value) -> reqwest::RequestBuilder {
+        let mut builder = client
+            .post(self.chat_url())
+            .header("Content-Type", "application/json");
+
+        match self.kind {
+            ProviderKind::Anthropic => {
+                builder = builder.header("anthropic-version", ANTHROPIC_VERSION);
+                if let Some(key) = &self.api_key {
+                    builder = builder.header("x-api-key", key);
+                }
+            }
+            _ => {
+                if let Some(key) = &self.api_key {
+                    builder = builder.header("Authorization", format!("Bearer {}", key));
+                }
+            }
+        }
+
+        if self.kind == ProviderKind::OpenRouter {
+            builder = builder
+                .header("HTTP-Referer", OPENROUTER_REFERER)
+                .header("X-Title", OPENROUTER_TITLE);
+        }
+
+        builder.json(body)
+    }
+
+    /// Extracts the response text and tool calls from a non-streamed response body
+    pub fn parse_completion(&self, json: &Value) -> Completion {
+        match self.kind {
+            ProviderKind::Anthropic => {
+                let blocks = json["content"].as_array().cloned().unwrap_or_default();
+                let content = blocks
+                    .iter()
+                    .filter(|b| b["type"] == "text")
+                    .filter_map(|b| b["text"].as_str())
+                    .collect::<String>();
+                let reasoning = blocks
+                    .iter()
+                    .filter(|b| b["type"] == "thinking")
+                    .filter_map(|b| b["thinking"].as_str())
+                    .collect::<String>();
+                let tool_calls = blocks
+                    .iter()
+                    .filter(|b| b["type"] == "tool_use")
+                    .map(|b| ToolCall {
+                        id: b["id"].as_str().unwrap_or_default().to_string(),
+                        name: b["name"].as_str().unwrap_or_default().to_string(),
+                        arguments: b["input"].to_string(),
+                    })
+                    .collect();
+                Completion {
+                    content,
+                    reasoning,
+                    tool_calls,
+                    usage: parse_anthropic_usage(&json["usage"]),
+                    finish_reason: json["stop_reason"].as_str().map(FinishReason::parse),
+                    model: json["model"].as_str().map(str::to_string),
+                }
+            }
+            _ => {
+                let message = &json["choices"][0]["message"];
+                let tool_calls = message["tool_calls"]
+                    .as_array()
+                    .map(|calls| {
+                        calls
+                            .iter()
+                            .map(|c| ToolCall {
+                                id: c["id"].as_str().unwrap_or_default().to_string(),
+                                name: c["function"]["name"]
+                                    .as_str()
+                                    .unwrap_or_default()
+                                    .to_string(),
+                                arguments: c["function"]["arguments"]
+                                    .as_str()
+                                    .unwrap_or_default()
+                                    .to_string(),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Completion {
+                    content: message["content"].as_str().unwrap_or_default().to_string(),
+                    reasoning: reasoning_text(message).unwrap_or_default().to_string(),
+                    tool_calls,
+                    usage: parse_openai_usage(&json["usage"]),
+                    finish_reason: json["choices"][0]["finish_reason"]
+                        .as_str()
+                        .map(FinishReason::parse),
+                    model: json["model"].as_str().map(str::to_string),
+                }
+            }
+        }
+    }
+
+    /// Extracts the text and tool call deltas from one streamed JSON event
+    pub fn parse_stream_event(&self, json: &Value) -> Vec<StreamDelta> {
+        let mut deltas = Vec::new();
+
+        match self.kind {
+            ProviderKind::Anthropic => {
+                let index = json["index"].as_u64().unwrap_or(0) as usize;
+                match json["type"].as_str() {
+                    Some("content_block_start") if json["content_block"]["type"] == "tool_use" => {
+                        deltas.push(StreamDelta::ToolCall {
+                            index,
+                            id: json["content_block"]["id"].as_str().map(str::to_string),
+                            name: json["content_block"]["name"].as_str().map(str::to_string),
+                            arguments: String::new(),
+                        });
+                    }
+                    Some("content_block_delta") => match json["delta"]["type"].as_str() {
+                        Some("text_delta") => {
+                            if let Some(text) = json["delta"]["text"].as_str() {
+                                deltas.push(StreamDelta::Text(text.to_string()));
+                            }
+                        }
+                        Some("thinking_delta") => {
+                            if let Some(thinking) = json["delta"]["thinking"].as_str() {
+                                deltas.push(StreamDelta::Reasoning(thinking.to_string()));
+                            }
+                        }
+                        Some("input_json_delta") => {
+                            deltas.push(StreamDelta::ToolCall {
+                                index,
+                                id: None,
+                                name: None,
+                                arguments: json["delta"]["partial_json"]
+                                    .as_str()
+                                    .unwrap_or_default()
+                                    .to_string(),
+                            });
+                        }
+                        _ => {}
+                    },
+                    // Input tokens arrive at the start, output tokens and the
+                    // stop reason in the final delta
+                    Some("message_start") => {
+                        if let Some(model) = json["message"]["model"].as_str() {
+                            deltas.push(StreamDelta::Model(model.to_string()));
+                        }
+                        deltas.extend(
+                            parse_anthropic_usage(&json["message"]["usage"])
+                                .map(StreamDelta::Usage),
+                        );
+                    }
+                    Some("message_delta") => {
+                        deltas
+                            .extend(parse_anthropic_usage(&json["usage"]).map(StreamDelta::Usage));
+                        if let Some(reason) = json["delta"]["stop_reason"].as_str() {
+                            deltas.push(StreamDelta::Finish(FinishReason::parse(reason)));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {
+                // Every chunk names the model; callers keep the first
+                if let Some(model) = json["model"].as_str() {
+                    deltas.push(StreamDelta::Model(model.to_string()));
+                }
+                let delta = &json["choices"][0]["delta"];
+                if let Some(reasoning) = reasoning_text(delta) {
+                    if !reasoning.is_empty() {
+                        deltas.push(StreamDelta::Reasoning(reasoning.to_string()));
+                    }
+                }
+                if let Some(text) = delta["content"].as_str() {
+                    if !text.is_empty() {
+                        deltas.push(StreamDelta::Text(text.to_string()));
+                    }
+                }
+                if let Some(calls) = delta["tool_calls"].as_array() {
+                    for (position, call) in calls.iter().enumerate() {
+                        deltas.push(StreamDelta::ToolCall {
+                            index: call["index"].as_u64().map_or(position, |i| i as usize),
+                            id: call["id"].as_str().map(str::to_string),
+                            name: call["function"]["name"].as_str().map(str::to_string),
+                            arguments: call["function"]["arguments"]
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                        });
+                    }
+                }
+                if let Some(reason) = json["choices"][0]["finish_reason"].as_str() {
+                    deltas.push(StreamDelta::Finish(FinishReason::parse(reason)));
+                }
+                // The usage chunk comes last, with an empty choices array
+                deltas.extend(parse_openai_usage(&json["usage"]).map(StreamDelta::Usage));
+            }
+        }
+
+        deltas
+    }
+}
+
+/// Reads the reasoning text of an OpenAI-style message or delta: OpenRouter
+/// uses `reasoning`, DeepSeek and llama.cpp-style servers `reasoning_content`
+fn reasoning_text(message: &Value) -> Option<&str> {
+    message["reasoning"]
+        .as_str()
+        .or_else(|| message["reasoning_content"].as_str())
+}
+
+/// Reads an OpenAI-style usage object, including OpenRouter's cost field
+fn parse_openai_usage(usage: &Value) -> Option<Usage> {
+    if !usage.is_object() {
+        return None;
+    }
+    Some(Usage {
+        prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0) as u32,
+        completion_tokens: usage["completion_tokens"].as_u64().unwrap_or(0) as u32,
+        cost: usage["cost"].as_f64(),
+    })
+}
+
+/// Reads an Anthropic usage object; cached input counts toward the prompt
+fn parse_anthropic_usage(usage: &Value) -> Option<Usage> {
+    if !usage.is_object() {
+        return None;
+    }
+    let prompt_tokens = [
+        "input_tokens",
+        "cache_creation_input_tokens",
+        "cache_read_input_tokens",
+    ]
+    .iter()
+    .map(|key| usage[key].as_u64().unwrap_or(0))
+    .sum::<u64>();
+    Some(Usage {
+        prompt_tokens: prompt_tokens as u32,
+        completion_tokens: usage["output_tokens"].as_u64().unwrap_or(0) as u32,
+        cost: None,
+    })
+}
+
+/// Builds the assistant message that records the tool calls it made
+pub fn assistant_tool_call_message(content: &str, tool_calls: &[ToolCall]) -> Value {
+    json!({
+        "role": "assistant",
+        "content": if content.is_empty() { Value::Null } else { json!(content) },
+        "tool_calls": tool_calls
+            .iter()
+            .map(|c| {
+                json!({
+                    "id": c.id,
+                    "type": "function",
+                    "function": { "name": c.name, "arguments": c.arguments }
+                })
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Builds the message that returns a tool's result to the model
+pub fn tool_result_message(tool_call_id: &str, result: &str) -> Value {
+    json!({
+        "role": "tool",
+        "tool_call_id": tool_call_id,
+        "content": result
+    })
+}
+
+/// Converts OpenAI-style messages to Anthropic's format.
+/// Returns the combined system prompt and the remaining messages.
+fn to_anthropic_messages(messages: &[Value]) -> (String, Vec<Value>) {
+    let mut system_parts: Vec<String> = Vec::new();
+    let mut converted: Vec<Value> = Vec::new();
+
+    for msg in messages {
+        let role = msg["role"].as_str().unwrap_or("user");
+
+        match role {
+            "system" => {
+                if let Some(text) = msg["content"].as_str() {
+                    system_parts.push(text.to_string());
+                }
+            }
+            "tool" => {
+                // Tool results travel as tool_result blocks in a user turn; results
+                // for several calls made in one turn share the same user message
+                let block = json!({
+                    "type": "tool_result",
+                    "tool_use_id": msg["tool_call_id"],
+                    "content": msg["content"]
+                });
+                let previous_is_result = converted.last().is_some_and(|m| {
+                    m["role"] == "user" && m["content"][0]["type"] == "tool_result"
+                });
+                if previous_is_result {
+                    if let Some(Value::Array(blocks)) =
+                        converted.last_mut().map(|m| &mut m["content"])
+                    {
+                        blocks.push(block);
+                    }
+                } else {
+                    converted.push(json!({ "role": "user", "content": [block] }));
+                }
+            }
+            "assistant" if msg["tool_calls"].is_array() => {
+                let mut blocks: Vec<Value> = Vec::new();
+                if let Some(text) = msg["content"].as_str().filter(|t| !t.is_empty()) {
+                    blocks.push(json!({ "type": "text", "text": text }));
+                }
+                for call in msg["tool_calls"].as_array().into_iter().flatten() {
+                    let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+                    let input =
+                        serde_json::from_str::<Value>(arguments).unwrap_or_else(|_| json!({}));
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call["id"],
+                        "name": call["function"]["name"],
+                        "input": input
+                    }));
+                }
+                converted.push(json!({ "role": "assistant", "content": blocks }));
+            }
+            _ => {
+                let content = match &msg["content"] {
+                    Value::Array(parts) => {
+                        Value::Array(parts.iter().map(to_anthropic_part).collect())
+                    }
+                    other => other.clone(),
+                };
+
+                converted.push(json!({
+                    "role": if role == "assistant" { "assistant" } else { "user" },
+                    "content": content
+                }));
+            }
+        }
+    }
+
+    (system_parts.join("\n\n"), converted)
+}
+
+/// Converts a single OpenAI content part (text or image_url) to an Anthropic content block
+fn to_anthropic_part(part: &Value) -> Value {
+    if part["type"] != "image_url" {
+        return part.clone();
+    }
+
+    let url = part["image_url"]["url"].as_str().unwrap_or_default();
+
+    // Data URLs become inline base64 images, anything else is passed by URL
+    if let Some(rest) = url.strip_prefix("data:") {
+        if let Some((media_type, data)) = rest.split_once(";base64,") {
+            return json!({
+                "type": "image",
+                "source": {
+                    "type": "base64",
+                    "media_type": media_type,
+                    "data": data
+                }
+            });
+        }
+    }
+
+    json!({
+        "type": "image",
+        "source": { "type": "url", "url": url }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_sampling(sampling: SamplingSettings) -> GenerationSettings {
+        GenerationSettings {
+            max_tokens: None,
+            sampling,
+        }
+    }
+
+    #[test]
+    fn accepts_sampling_within_range() {
+        assert!(GenerationSettings::default().validate().is_ok());
+        let settings = with_sampling(SamplingSettings {
+            temperature: Some(2.0),
+            top_p: Some(0.0),
+            presence_penalty: Some(-2.0),
+            frequency_penalty: Some(1.5),
+            ..Default::default()
+        });
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_sampling_out_of_range() {
+        for sampling in [
+            SamplingSettings {
+                temperature: Some(-0.1),
+                ..Default::default()
+            },
+            SamplingSettings {
+                temperature: Some(f32::NAN),
+                ..Default::default()
+            },
+            SamplingSettings {
+                top_p: Some(1.5),
+                ..Default::default()
+            },
+            SamplingSettings {
+                frequency_penalty: Some(3.0),
+                ..Default::default()
+            },
+        ] {
+            assert!(
+                with_sampling(sampling.clone()).validate().is_err(),
+                "{:?}",
+                sampling
+            );
+        }
+    }
+
+    #[test]
+    fn final_answer_keeps_tools_but_forbids_calling_them() {
+        let request = ChatRequest {
+            model: "m".to_string(),
+            messages: vec![json!({ "role": "user", "content": "hi" })],
+            max_tokens: 100,
+            stream: false,
+            tools: vec![ToolDefinition {
+                name: "get_current_time".to_string(),
+                description: "Time".to_string(),
+                parameters: json!({ "type": "object", "properties": {} }),
+            }],
+            reasoning_effort: None,
+            sampling: SamplingSettings::default(),
+            fallback_models: Vec::new(),
+            forbid_tool_calls: true,
+        };
+        let provider = |kind| LlmProvider {
+            kind,
+            base_url: String::new(),
+            api_key: None,
+            routing: OpenRouterRouting::default(),
+        };
+
+        let body = provider(ProviderKind::OpenAI).build_body(&request);
+        assert_eq!(body["tools"].as_array().unwrap().len(), 1);
+        assert_eq!(body["tool_choice"], json!("none"));
+
+        let body = provider(ProviderKind::Anthropic).build_body(&request);
+        assert_eq!(body["tools"].as_array().unwrap().len(), 1);
+        assert_eq!(body["tool_choice"], json!({ "type": "none" }));
+    }
+}
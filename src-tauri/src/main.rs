@@ -2,24 +2,61 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 // Module declarations
+mod attachments;
+mod catalog;
+mod commentary;
+mod context;
 mod db;
+mod emotions;
+mod limits;
+mod llm;
+mod migrations;
 mod models;
 mod paths;
 mod prompts;
+mod retry;
+mod streaming;
+mod templates;
+mod tools;
 
 // Re-exports for internal use
-use db::{clear_chat_history_internal, get_chat_history_internal, store_chat_message};
-use models::{ChatMessage, ChatResponse};
+use attachments::{prepare_attachments, remove_stored_copies, store_copy, PreparedAttachment};
+use catalog::{ModelFilters, ModelOption};
+use db::{
+    archive_conversation_internal, clear_chat_history_internal, create_conversation_internal,
+    delete_conversation_internal, get_chat_history_internal, get_chat_history_page_internal,
+    get_chat_messages_between, get_conversation, get_conversation_summary,
+    get_message_attachments_internal, get_message_internal, get_messages_around_internal,
+    get_usage_report_internal, list_conversations_internal, record_usage,
+    rename_conversation_internal, save_conversation_summary, search_chat_history_internal,
+    set_conversation_persona_internal, store_chat_message, store_message_attachments, Db,
+};
+use emotions::{EmotionMap, EmotionStripper, ModelEmotionAssets};
+use limits::{check_limits, count_request, SpendingLimits, BUILTIN_KEY_LIMITS};
+use llm::{
+    ChatRequest, GenerationSettings, LlmProvider, OpenRouterRouting, ProviderKind, ReasoningEffort,
+    SamplingSettings, StreamDelta, ToolCall, ToolCallAccumulator, ToolDefinition,
+};
+use models::{
+    ChatMessage, ChatResponse, ChatSearchHit, Conversation, ConversationSummary, FallbackHop,
+    HistoryFilters, HistoryPage, MessageAttachment, NewChatMessage, Usage, UsageReportRow,
+};
 use paths::*;
 use prompts::*;
+use retry::RetryPolicy;
+use streaming::{ChatStreamEvent, FinishReason, SseDecoder};
+use templates::TemplateContext;
+use tools::tool_registry;
 
 use rdev::{listen, Event, EventType};
 use serde::Serialize;
 use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
 use std::io::{Read, Write as IoWrite};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Mutex;
+use std::time::Instant;
 #[cfg(target_os = "windows")]
 use tauri::http::Response;
 use tauri::menu::{Menu, MenuItem};
@@ -28,6 +65,7 @@ use tauri::{command, AppHandle, Emitter, Manager};
 use tauri_plugin_autostart::MacosLauncher;
 use tauri_plugin_global_shortcut::{Code, GlobalShortcutExt, Modifiers, Shortcut, ShortcutState};
 // rusqlite is now used in db.rs module
+use futures_util::future::{AbortHandle, Abortable, Aborted};
 use futures_util::StreamExt;
 use log::{error, info, warn};
 use serde::Deserialize;
@@ -171,8 +209,60 @@ pub struct LLMConfig {
     pub assistant_model: String,
     #[serde(default = "default_rp_model")]
     pub rp_model: String,
+    #[serde(default)]
+    pub assistant_provider: ProviderKind,
+    #[serde(default)]
+    pub rp_provider: ProviderKind,
     pub openrouter_api_key: Option<String>,
     pub openai_api_key: Option<String>,
+    pub anthropic_api_key: Option<String>,
+    /// Base URL (including any /v1 suffix) for the OpenAI-compatible provider
+    pub custom_base_url: Option<String>,
+    pub custom_api_key: Option<String>,
+    /// Root URL (without /v1) of the local model server
+    pub local_base_url: Option<String>,
+    /// Upper bound on prompt tokens per request, applied below the model's context length
+    pub context_budget_tokens: Option<u32>,
+    /// Context lengths for models the catalog doesn't know (e.g. local models)
+    #[serde(default)]
+    pub model_context_lengths: HashMap<String, u32>,
+    /// Token, spend and request rate caps checked before each request
+    #[serde(default)]
+    pub spending_limits: SpendingLimits,
+    /// Whether the character comments on assistant-level replies
+    #[serde(default = "default_character_commentary")]
+    pub character_commentary: bool,
+    /// Whether character replies carry emotion markers that drive the avatar
+    #[serde(default = "default_character_emotions")]
+    pub character_emotions: bool,
+    /// Reasoning effort requested for replies at each level; `None` leaves it to the model
+    pub assistant_reasoning_effort: Option<ReasoningEffort>,
+    pub rp_reasoning_effort: Option<ReasoningEffort>,
+    /// Reply length and sampling for each level
+    #[serde(default = "default_assistant_generation")]
+    pub assistant_generation: GenerationSettings,
+    #[serde(default = "default_rp_generation")]
+    pub rp_generation: GenerationSettings,
+    /// Name the prompts use for the user; falls back to the OS account name
+    pub user_name: Option<String>,
+    /// Models tried in order when a level's model fails or is over capacity
+    #[serde(default)]
+    pub assistant_fallback_models: Vec<String>,
+    #[serde(default)]
+    pub rp_fallback_models: Vec<String>,
+    /// Retries of transient failures before moving on to a fallback model
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// Vision model that describes screenshots for levels whose model can't see them
+    pub vision_relay_model: Option<String>,
+    #[serde(default)]
+    pub vision_relay_provider: ProviderKind,
+    /// Model ids starred in the model pickers
+    #[serde(default)]
+    pub favorite_models: Vec<String>,
+    /// Provider preferences, transforms and caching hints for OpenRouter requests
+    #[serde(default)]
+    pub openrouter_routing: OpenRouterRouting,
     // Legacy field for migration
     #[serde(skip_serializing, default)]
     chat_model: Option<String>,
@@ -186,13 +276,125 @@ fn default_rp_model() -> String {
     "openai/chatgpt-4o-latest".to_string()
 }
 
+fn default_character_commentary() -> bool {
+    true
+}
+
+fn default_character_emotions() -> bool {
+    true
+}
+
+/// The assistant gets room for longer answers such as code
+fn default_assistant_generation() -> GenerationSettings {
+    GenerationSettings {
+        max_tokens: Some(2000),
+        ..Default::default()
+    }
+}
+
+/// The character is livelier than the model default
+fn default_rp_generation() -> GenerationSettings {
+    GenerationSettings {
+        max_tokens: None,
+        sampling: SamplingSettings {
+            temperature: Some(0.9),
+            ..Default::default()
+        },
+    }
+}
+
+impl LLMConfig {
+    /// Model selected for a context level
+    fn model_for_level(&self, context_level: u8) -> &str {
+        match context_level {
+            1 => &self.rp_model,
+            _ => &self.assistant_model,
+        }
+    }
+
+    /// Fallback models for a context level, in the order they are tried
+    fn fallback_models_for_level(&self, context_level: u8) -> &[String] {
+        match context_level {
+            1 => &self.rp_fallback_models,
+            _ => &self.assistant_fallback_models,
+        }
+    }
+
+    /// Provider selected for a context level
+    fn provider_for_level(&self, context_level: u8) -> ProviderKind {
+        match context_level {
+            1 => self.rp_provider,
+            _ => self.assistant_provider,
+        }
+    }
+
+    /// Reasoning effort selected for a context level
+    fn reasoning_effort_for_level(&self, context_level: u8) -> Option<ReasoningEffort> {
+        match context_level {
+            1 => self.rp_reasoning_effort,
+            _ => self.assistant_reasoning_effort,
+        }
+    }
+
+    /// Generation settings selected for a context level
+    fn generation_for_level(&self, context_level: u8) -> &GenerationSettings {
+        match context_level {
+            1 => &self.rp_generation,
+            _ => &self.assistant_generation,
+        }
+    }
+
+    /// Reply length for a context level
+    fn max_tokens_for_level(&self, context_level: u8) -> u32 {
+        self.generation_for_level(context_level)
+            .max_tokens
+            .filter(|t| *t > 0)
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+
+    /// Root URL of the local model server, falling back to the Ollama default
+    fn local_base_url(&self) -> String {
+        self.local_base_url
+            .clone()
+            .unwrap_or_else(|| llm::DEFAULT_LOCAL_BASE_URL.to_string())
+    }
+
+    /// Whether any context level is configured to use the local server
+    fn uses_local_provider(&self) -> bool {
+        self.assistant_provider == ProviderKind::Local || self.rp_provider == ProviderKind::Local
+    }
+}
+
 impl Default for LLMConfig {
     fn default() -> Self {
         Self {
             assistant_model: default_assistant_model(),
             rp_model: default_rp_model(),
+            assistant_provider: ProviderKind::default(),
+            rp_provider: ProviderKind::default(),
             openrouter_api_key: None,
             openai_api_key: None,
+            anthropic_api_key: None,
+            custom_base_url: None,
+            custom_api_key: None,
+            local_base_url: None,
+            context_budget_tokens: None,
+            model_context_lengths: HashMap::new(),
+            spending_limits: SpendingLimits::default(),
+            character_commentary: default_character_commentary(),
+            character_emotions: default_character_emotions(),
+            assistant_reasoning_effort: None,
+            rp_reasoning_effort: None,
+            assistant_generation: default_assistant_generation(),
+            rp_generation: default_rp_generation(),
+            user_name: None,
+            assistant_fallback_models: Vec::new(),
+            rp_fallback_models: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            vision_relay_model: None,
+            vision_relay_provider: ProviderKind::default(),
+            favorite_models: Vec::new(),
+            openrouter_routing: OpenRouterRouting::default(),
             chat_model: None,
         }
     }
@@ -550,6 +752,64 @@ async fn is_initialized() -> Result<bool, String> {
 
 // ============ Model Config Commands ============
 
+/// Expressions and motion groups declared by the current Live2D model
+fn current_model_assets(config: &ModelConfig) -> Result<ModelEmotionAssets, String> {
+    let model_file = get_models_dir()?
+        .join(&config.folder)
+        .join(&config.model_file);
+    emotions::read_model_assets(&model_file)
+}
+
+/// Emotion mapping for the current Live2D model, guessed from its
+/// expression and motion names until the user saves one
+fn load_emotion_map() -> Result<EmotionMap, String> {
+    let config = load_model_config()?;
+    let map_path = get_emotion_map_path(&config.folder)?;
+    if map_path.exists() {
+        let content = std::fs::read_to_string(&map_path)
+            .map_err(|e| format!("Failed to read emotion mapping: {}", e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Failed to parse emotion mapping: {}", e))
+    } else {
+        Ok(emotions::default_mapping(&current_model_assets(&config)?))
+    }
+}
+
+#[derive(Serialize)]
+struct EmotionMappingInfo {
+    /// Emotions the character can express
+    emotions: Vec<String>,
+    /// What the current model offers to map them to
+    assets: ModelEmotionAssets,
+    mapping: EmotionMap,
+}
+
+#[command]
+async fn get_emotion_mapping() -> Result<EmotionMappingInfo, String> {
+    let config = load_model_config()?;
+    Ok(EmotionMappingInfo {
+        emotions: emotions::EMOTIONS.iter().map(|e| e.to_string()).collect(),
+        assets: current_model_assets(&config)?,
+        mapping: load_emotion_map()?,
+    })
+}
+
+/// Saves the emotion mapping for the current Live2D model
+#[command]
+async fn save_emotion_mapping(mapping: EmotionMap) -> Result<(), String> {
+    let config = load_model_config()?;
+    let map_path = get_emotion_map_path(&config.folder)?;
+    if let Some(parent) = map_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+    let content = serde_json::to_string_pretty(&mapping)
+        .map_err(|e| format!("Failed to serialize emotion mapping: {}", e))?;
+    std::fs::write(&map_path, content)
+        .map_err(|e| format!("Failed to save emotion mapping: {}", e))?;
+    Ok(())
+}
+
 #[command]
 async fn get_model_config() -> Result<ModelConfig, String> {
     let config = load_model_config()?;
@@ -803,13 +1063,11 @@ async fn get_api_key() -> Result<Option<String>, String> {
 
 #[command]
 async fn has_api_key() -> Result<bool, String> {
-    // Check for built-in key first
-    if get_builtin_api_key().is_some() {
-        return Ok(true);
-    }
-    // Fall back to checking LLM config
+    // Every context level must resolve to a usable provider. For the default
+    // OpenRouter setup this is the built-in or user-configured key; local
+    // servers need no key at all.
     let config = load_llm_config()?;
-    Ok(config.openrouter_api_key.is_some())
+    Ok(resolve_provider(&config, 0).is_ok() && resolve_provider(&config, 1).is_ok())
 }
 
 // ============ OpenAI Key Commands (for image editing) ============
@@ -846,15 +1104,63 @@ async fn has_openai_key() -> Result<bool, String> {
     Ok(config.openai_api_key.is_some())
 }
 
-// ============ LLM Model Selection Commands ============
+// ============ Anthropic / Custom Endpoint Commands ============
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ModelOption {
-    pub id: String,
-    pub name: String,
-    pub supports_vision: bool,
+#[command]
+async fn save_anthropic_key(key: String) -> Result<(), String> {
+    info!("[save_anthropic_key] Saving Anthropic API key");
+    let mut config = load_llm_config()?;
+    config.anthropic_api_key = Some(key);
+    save_llm_config(&config)?;
+    info!("[save_anthropic_key] Anthropic API key saved successfully");
+    Ok(())
+}
+
+#[command]
+async fn has_anthropic_key() -> Result<bool, String> {
+    let config = load_llm_config()?;
+    Ok(config.anthropic_api_key.is_some())
+}
+
+#[command]
+async fn set_custom_endpoint(base_url: String, api_key: Option<String>) -> Result<(), String> {
+    info!(
+        "[set_custom_endpoint] Setting OpenAI-compatible base URL: {}",
+        base_url
+    );
+    let mut config = load_llm_config()?;
+    let base_url = base_url.trim().trim_end_matches('/').to_string();
+    config.custom_base_url = if base_url.is_empty() {
+        None
+    } else {
+        Some(base_url)
+    };
+    config.custom_api_key = api_key.filter(|k| !k.trim().is_empty());
+    save_llm_config(&config)?;
+    Ok(())
+}
+
+#[command]
+async fn set_local_base_url(base_url: String) -> Result<(), String> {
+    info!(
+        "[set_local_base_url] Setting local server URL: {}",
+        base_url
+    );
+    let mut config = load_llm_config()?;
+    let base_url = base_url.trim().trim_end_matches('/').to_string();
+    // Accept URLs copied with the /v1 suffix as well
+    let base_url = base_url.trim_end_matches("/v1").to_string();
+    config.local_base_url = if base_url.is_empty() {
+        None
+    } else {
+        Some(base_url)
+    };
+    save_llm_config(&config)?;
+    Ok(())
 }
 
+// ============ LLM Model Selection Commands ============
+
 #[command]
 async fn get_llm_config_cmd() -> Result<LLMConfig, String> {
     load_llm_config()
@@ -878,70 +1184,270 @@ async fn set_model(model: String, context_level: u8) -> Result<(), String> {
 }
 
 #[command]
-async fn get_model_supports_vision(model_id: String) -> Result<bool, String> {
-    // Fetch models from OpenRouter API to check vision support
-    let models = get_available_models().await?;
-    Ok(models
-        .iter()
-        .find(|m| m.id == model_id)
-        .map(|m| m.supports_vision)
-        .unwrap_or(false))
+async fn set_provider(provider: ProviderKind, context_level: u8) -> Result<(), String> {
+    info!(
+        "[set_provider] Setting provider to: {:?} for context level: {}",
+        provider, context_level
+    );
+    let mut config = load_llm_config()?;
+    match context_level {
+        0 => config.assistant_provider = provider,
+        1 => config.rp_provider = provider,
+        _ => return Err(format!("Invalid context level: {}", context_level)),
+    }
+    save_llm_config(&config)?;
+    info!("[set_provider] Provider updated successfully");
+    Ok(())
 }
 
 #[command]
-async fn get_available_models() -> Result<Vec<ModelOption>, String> {
-    // Fetch models from OpenRouter API
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://openrouter.ai/api/v1/models")
-        .header("Content-Type", "application/json")
-        .send()
-        .await
-        .map_err(|e| format!("Failed to fetch models: {}", e))?;
+async fn set_reasoning_effort(
+    effort: Option<ReasoningEffort>,
+    context_level: u8,
+) -> Result<(), String> {
+    info!(
+        "[set_reasoning_effort] Setting level {} reasoning effort to: {:?}",
+        context_level, effort
+    );
+    let mut config = load_llm_config()?;
+    match context_level {
+        0 => config.assistant_reasoning_effort = effort,
+        1 => config.rp_reasoning_effort = effort,
+        _ => return Err(format!("Invalid context level: {}", context_level)),
+    }
+    save_llm_config(&config)?;
+    Ok(())
+}
 
-    if !response.status().is_success() {
-        return Err(format!("OpenRouter API error: {}", response.status()));
+#[command]
+async fn set_generation_settings(
+    settings: GenerationSettings,
+    context_level: u8,
+) -> Result<(), String> {
+    info!(
+        "[set_generation_settings] Setting level {} generation to: {:?}",
+        context_level, settings
+    );
+    settings.validate()?;
+    let mut config = load_llm_config()?;
+    match context_level {
+        0 => config.assistant_generation = settings,
+        1 => config.rp_generation = settings,
+        _ => return Err(format!("Invalid context level: {}", context_level)),
     }
+    save_llm_config(&config)?;
+    Ok(())
+}
 
-    let json: Value = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse models response: {}", e))?;
+#[command]
+async fn set_context_budget(budget_tokens: Option<u32>) -> Result<(), String> {
+    info!(
+        "[set_context_budget] Setting context budget to: {:?}",
+        budget_tokens
+    );
+    let mut config = load_llm_config()?;
+    config.context_budget_tokens = budget_tokens.filter(|b| *b > 0);
+    save_llm_config(&config)?;
+    Ok(())
+}
 
-    let models = json["data"]
-        .as_array()
-        .ok_or_else(|| "Invalid response format".to_string())?;
+#[command]
+async fn set_model_context_length(
+    model: String,
+    context_length: Option<u32>,
+) -> Result<(), String> {
+    let mut config = load_llm_config()?;
+    match context_length {
+        Some(length) if length > 0 => {
+            config.model_context_lengths.insert(model, length);
+        }
+        _ => {
+            config.model_context_lengths.remove(&model);
+        }
+    }
+    save_llm_config(&config)?;
+    Ok(())
+}
 
-    let mut result: Vec<ModelOption> = models
-        .iter()
-        .filter_map(|m| {
-            let id = m["id"].as_str()?.to_string();
-            let name = m["name"].as_str().unwrap_or(&id).to_string();
-
-            // Check if model supports vision by looking at architecture.input_modalities
-            let supports_vision = m["architecture"]["input_modalities"]
-                .as_array()
-                .map(|modalities| {
-                    modalities
-                        .iter()
-                        .any(|mod_val| mod_val.as_str() == Some("image"))
-                })
-                .unwrap_or(false);
+#[command]
+async fn set_spending_limits(limits: SpendingLimits) -> Result<(), String> {
+    info!(
+        "[set_spending_limits] Setting spending limits to: {:?}",
+        limits
+    );
+    limits.validate()?;
+    let mut config = load_llm_config()?;
+    config.spending_limits = limits;
+    save_llm_config(&config)?;
+    Ok(())
+}
 
-            Some(ModelOption {
-                id,
-                name,
-                supports_vision,
-            })
-        })
+#[command]
+async fn set_character_emotions(enabled: bool) -> Result<(), String> {
+    info!("[set_character_emotions] Setting emotions to: {}", enabled);
+    let mut config = load_llm_config()?;
+    config.character_emotions = enabled;
+    save_llm_config(&config)?;
+    Ok(())
+}
+
+#[command]
+async fn set_character_commentary(enabled: bool) -> Result<(), String> {
+    info!(
+        "[set_character_commentary] Setting commentary to: {}",
+        enabled
+    );
+    let mut config = load_llm_config()?;
+    config.character_commentary = enabled;
+    save_llm_config(&config)?;
+    Ok(())
+}
+
+/// Sets the models tried, in order, when a context level's model fails
+#[command]
+async fn set_fallback_models(models: Vec<String>, context_level: u8) -> Result<(), String> {
+    info!(
+        "[set_fallback_models] Setting level {} fallbacks to: {:?}",
+        context_level, models
+    );
+    let models: Vec<String> = models
+        .into_iter()
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
         .collect();
+    let mut config = load_llm_config()?;
+    match context_level {
+        0 => config.assistant_fallback_models = models,
+        1 => config.rp_fallback_models = models,
+        _ => return Err(format!("Invalid context level: {}", context_level)),
+    }
+    save_llm_config(&config)?;
+    Ok(())
+}
+
+/// Sets the vision model that describes screenshots for text-only models;
+/// `None` turns the relay off
+#[command]
+async fn set_vision_relay(
+    model: Option<String>,
+    provider: Option<ProviderKind>,
+) -> Result<(), String> {
+    info!(
+        "[set_vision_relay] Setting relay to: {:?} via {:?}",
+        model, provider
+    );
+    let mut config = load_llm_config()?;
+    config.vision_relay_model = model
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty());
+    if let Some(provider) = provider {
+        config.vision_relay_provider = provider;
+    }
+    save_llm_config(&config)?;
+    Ok(())
+}
+
+/// Sets the OpenRouter provider preferences, transforms and caching hints
+#[command]
+async fn set_openrouter_routing(routing: OpenRouterRouting) -> Result<(), String> {
+    info!("[set_openrouter_routing] Setting routing to: {:?}", routing);
+    let mut config = load_llm_config()?;
+    config.openrouter_routing = routing;
+    save_llm_config(&config)?;
+    Ok(())
+}
+
+#[command]
+async fn set_user_name(name: Option<String>) -> Result<(), String> {
+    let mut config = load_llm_config()?;
+    config.user_name = name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    save_llm_config(&config)?;
+    Ok(())
+}
+
+#[command]
+async fn get_model_supports_vision(model_id: String) -> Result<bool, String> {
+    // Look the model up in the cached listing of the configured providers
+    Ok(find_catalog_model(&model_id)
+        .await
+        .is_some_and(|m| m.supports_vision))
+}
+
+/// Lists the models of the configured providers. The OpenRouter list comes
+/// from the on-disk cache unless `refresh` is set or the cache has expired.
+#[command]
+async fn get_available_models(refresh: Option<bool>) -> Result<Vec<ModelOption>, String> {
+    let config = load_llm_config()?;
+    let uses_remote = config.assistant_provider != ProviderKind::Local
+        || config.rp_provider != ProviderKind::Local;
+
+    let mut result: Vec<ModelOption> = Vec::new();
+
+    if config.uses_local_provider() {
+        result.extend(catalog::fetch_local_models(&config.local_base_url()).await?);
+    }
+
+    // Air-gapped setups where every level is local never touch the network
+    if uses_remote {
+        match catalog::openrouter_models(refresh.unwrap_or(false)).await {
+            Ok(models) => result.extend(models),
+            // Still offer the local models when the remote catalog is unreachable
+            Err(e) if !result.is_empty() => {
+                warn!("[get_available_models] Skipping OpenRouter models: {}", e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
 
     // Sort by name for easier browsing
     result.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
 
+    catalog::remember_listing(&result);
+
     Ok(result)
 }
 
+/// Searches the available models by name and capability, e.g. vision models
+/// with tool support under a price
+#[command]
+async fn search_models(
+    query: Option<String>,
+    filters: Option<ModelFilters>,
+) -> Result<Vec<ModelOption>, String> {
+    let models = get_available_models(None).await?;
+    let config = load_llm_config()?;
+    Ok(catalog::search(
+        &models,
+        query.as_deref().unwrap_or(""),
+        &filters.unwrap_or_default(),
+        &config.favorite_models,
+    ))
+}
+
+/// Stars or unstars a model in the pickers, returning the updated favorites
+#[command]
+async fn set_model_favorite(model_id: String, favorite: bool) -> Result<Vec<String>, String> {
+    let mut config = load_llm_config()?;
+    config.favorite_models.retain(|id| *id != model_id);
+    if favorite {
+        config.favorite_models.push(model_id);
+    }
+    save_llm_config(&config)?;
+    Ok(config.favorite_models)
+}
+
+/// Catalog entry for a model id, listing the models on first use. A failed
+/// listing isn't retried here until the catalog TTL passes.
+async fn find_catalog_model(model_id: &str) -> Option<ModelOption> {
+    if catalog::needs_listing() {
+        if let Err(e) = get_available_models(None).await {
+            warn!("[catalog] Could not list models: {}", e);
+            catalog::remember_listing_failure();
+        }
+    }
+    catalog::find_listed(model_id)
+}
+
 // ============ Prompt Commands ============
 
 #[command]
@@ -1043,22 +1549,202 @@ async fn get_dialogue_prompt() -> Result<String, String> {
     }
 }
 
-// ============ Frontend Logging ============
+/// Reads the named snippets that prompts can include with `{{> name}}`
+fn load_prompt_snippets() -> Result<HashMap<String, String>, String> {
+    let dir = get_prompt_snippets_dir()?;
+    let mut snippets = HashMap::new();
+    if !dir.exists() {
+        return Ok(snippets);
+    }
 
-#[command]
-fn log_from_frontend(level: String, message: String) {
-    match level.as_str() {
-        "error" => error!("[Frontend] {}", message),
-        "warn" => warn!("[Frontend] {}", message),
-        _ => info!("[Frontend] {}", message),
+    let entries = std::fs::read_dir(&dir).map_err(|e| format!("Failed to read snippets: {}", e))?;
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().and_then(|e| e.to_str()) != Some("md") {
+            continue;
+        }
+        if let (Some(name), Ok(content)) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            std::fs::read_to_string(&path),
+        ) {
+            snippets.insert(name.to_string(), content.trim().to_string());
+        }
     }
+    Ok(snippets)
 }
 
-// ============ Hitbox Commands ============
+#[command]
+async fn list_prompt_snippets() -> Result<HashMap<String, String>, String> {
+    load_prompt_snippets()
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Point2D {
-    x: f64,
+/// Saves a named prompt snippet; empty content deletes it
+#[command]
+async fn save_prompt_snippet(name: String, content: String) -> Result<(), String> {
+    let name = name.trim();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!(
+            "Invalid snippet name '{}': use letters, digits, '-' and '_'",
+            name
+        ));
+    }
+
+    let dir = get_prompt_snippets_dir()?;
+    let path = dir.join(format!("{}.md", name));
+    if content.trim().is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path).map_err(|e| format!("Failed to delete snippet: {}", e))?;
+        }
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+    std::fs::write(&path, content).map_err(|e| format!("Failed to save snippet: {}", e))?;
+    Ok(())
+}
+
+/// Title of the focused window in another app, if the platform can tell
+fn active_window_title() -> Option<String> {
+    #[cfg(target_os = "windows")]
+    {
+        use windows::Win32::UI::WindowsAndMessaging::{
+            GetForegroundWindow, GetWindowTextW, GetWindowThreadProcessId,
+        };
+        unsafe {
+            let hwnd = GetForegroundWindow();
+            let mut pid = 0u32;
+            GetWindowThreadProcessId(hwnd, Some(&mut pid as *mut u32));
+            if pid == std::process::id() {
+                return None;
+            }
+            let mut buffer = [0u16; 512];
+            let len = GetWindowTextW(hwnd, &mut buffer);
+            let title = String::from_utf16_lossy(&buffer[..len.max(0) as usize]);
+            Some(title).filter(|t| !t.is_empty())
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        // lsappinfo needs no accessibility permission, unlike System Events
+        let front = std::process::Command::new("lsappinfo")
+            .arg("front")
+            .output()
+            .ok()?;
+        let asn = String::from_utf8_lossy(&front.stdout).trim().to_string();
+        let info = std::process::Command::new("lsappinfo")
+            .args(["info", "-only", "name", &asn])
+            .output()
+            .ok()?;
+        // Output looks like "LSDisplayName"="Safari"
+        let name = String::from_utf8_lossy(&info.stdout)
+            .split_once('=')
+            .map(|(_, value)| value.trim().trim_matches('"').to_string())?;
+        Some(name).filter(|n| !n.is_empty() && n != "Oto Desktop")
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let output = std::process::Command::new("xdotool")
+            .args(["getactivewindow", "getwindowname"])
+            .output()
+            .ok()?;
+        let title = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Some(title).filter(|t| !t.is_empty() && t != "Oto Desktop")
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+    {
+        None
+    }
+}
+
+/// Variables and snippets available to prompts at a context level
+fn prompt_template_context(context_level: u8, template: &str) -> Result<TemplateContext, String> {
+    let config = load_llm_config()?;
+    let now = chrono::Local::now();
+    let user_name = config.user_name.clone().unwrap_or_else(|| {
+        std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_default()
+    });
+    let os = match std::env::consts::OS {
+        "macos" => "macOS",
+        "windows" => "Windows",
+        "linux" => "Linux",
+        other => other,
+    };
+
+    let mut variables = HashMap::from([
+        ("now".to_string(), now.format("%Y-%m-%d %H:%M").to_string()),
+        ("weekday".to_string(), now.format("%A").to_string()),
+        ("user_name".to_string(), user_name),
+        ("os".to_string(), os.to_string()),
+        (
+            "model".to_string(),
+            config.model_for_level(context_level).to_string(),
+        ),
+    ]);
+    let snippets = load_prompt_snippets()?;
+
+    // Looking up the focused window spawns a process on some platforms
+    if template.contains("active_window") || snippets.values().any(|s| s.contains("active_window"))
+    {
+        variables.insert(
+            "active_window".to_string(),
+            active_window_title().unwrap_or_default(),
+        );
+    }
+
+    Ok(TemplateContext {
+        variables,
+        snippets,
+    })
+}
+
+/// Renders a prompt template with the current runtime variables
+fn render_prompt(template: &str, context_level: u8) -> Result<String, String> {
+    let context = prompt_template_context(context_level, template)?;
+    Ok(templates::render(template, &context).trim().to_string())
+}
+
+/// Shows the final text of the "system", "character" or "dialogue" prompt.
+/// Pass `template` to preview unsaved edits instead of the stored prompt.
+#[command]
+async fn render_prompt_preview(
+    prompt_type: String,
+    context_level: Option<u8>,
+    template: Option<String>,
+) -> Result<String, String> {
+    let (stored, default_level) = match prompt_type.as_str() {
+        "system" => (get_system_prompt().await?, 0),
+        "character" => (get_character_prompt().await?, 1),
+        "dialogue" => (get_dialogue_prompt().await?, 1),
+        other => return Err(format!("Unknown prompt type: {}", other)),
+    };
+    let template = template.unwrap_or(stored);
+    render_prompt(&template, context_level.unwrap_or(default_level))
+}
+
+// ============ Frontend Logging ============
+
+#[command]
+fn log_from_frontend(level: String, message: String) {
+    match level.as_str() {
+        "error" => error!("[Frontend] {}", message),
+        "warn" => warn!("[Frontend] {}", message),
+        _ => info!("[Frontend] {}", message),
+    }
+}
+
+// ============ Hitbox Commands ============
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Point2D {
+    x: f64,
     y: f64,
 }
 
@@ -1116,359 +1802,1890 @@ async fn clear_hitbox() -> Result<(), String> {
     Ok(())
 }
 
-// ============ Chat Commands ============
+// ============ Conversation Commands ============
 
-const OPENROUTER_REFERER: &str = "https://oto.frisson.app";
-const OPENROUTER_TITLE: &str = "Oto Desktop";
+/// Title given to conversations created without one
+const DEFAULT_CONVERSATION_TITLE: &str = "New chat";
 
-async fn call_openrouter_chat(
-    messages: Vec<Value>,
-    max_tokens: u32,
-    stream: bool,
-    context_level: u8,
-) -> Result<reqwest::Response, String> {
-    let config = load_llm_config()?;
-    // Use built-in key if available, otherwise use user-configured key
-    let api_key = get_builtin_api_key()
-        .or(config.openrouter_api_key)
-        .ok_or_else(|| "OpenRouter API key not configured".to_string())?;
-
-    // Select model based on context level
-    let model = match context_level {
-        1 => config.rp_model,
-        _ => config.assistant_model,
-    };
+/// Reads the id of the active conversation saved by `switch_conversation`
+fn load_active_conversation_id() -> Option<i64> {
+    let path = get_active_conversation_path().ok()?;
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
 
-    let client = reqwest::Client::new();
+fn save_active_conversation_id(conversation_id: i64) -> Result<(), String> {
+    let path = get_active_conversation_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+    std::fs::write(&path, conversation_id.to_string())
+        .map_err(|e| format!("Failed to save active conversation: {}", e))
+}
 
-    let mut body = json!({
-        "model": model,
-        "messages": messages,
-        "max_tokens": max_tokens
-    });
+/// Looks up the given conversation, or the active one when `None`. Without a
+/// usable active conversation, the most recent one becomes active, and a new
+/// one is started when none exist.
+fn resolve_conversation(db: &Db, conversation_id: Option<i64>) -> Result<Conversation, String> {
+    if let Some(id) = conversation_id {
+        return get_conversation(db, id)?.ok_or_else(|| format!("Conversation {} not found", id));
+    }
 
-    if stream {
-        body["stream"] = json!(true);
+    if let Some(id) = load_active_conversation_id() {
+        if let Some(conversation) = get_conversation(db, id)? {
+            return Ok(conversation);
+        }
     }
 
-    client
-        .post("https://openrouter.ai/api/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("HTTP-Referer", OPENROUTER_REFERER)
-        .header("X-Title", OPENROUTER_TITLE)
-        .header("Content-Type", "application/json")
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| format!("API request failed: {}", e))
+    let conversation = match list_conversations_internal(db, false)?.into_iter().next() {
+        Some(conversation) => conversation,
+        None => create_conversation_internal(
+            db,
+            DEFAULT_CONVERSATION_TITLE,
+            None,
+            &chrono::Utc::now().to_rfc3339(),
+        )?,
+    };
+    save_active_conversation_id(conversation.id)?;
+    Ok(conversation)
 }
 
+/// Lists conversations, most recently active first
 #[command]
-async fn send_chat_message(
-    app: AppHandle,
-    message: String,
-    include_screenshot: bool,
-    context_level: u8,
-) -> Result<ChatResponse, String> {
-    // Get system prompt based on level
-    let system_prompt = match context_level {
-        1 => {
-            // Level 1: Use dialogue prompt (respond AS the character in direct conversation)
-            get_dialogue_prompt().await?
-        }
-        _ => {
-            // Level 0: Default system prompt
-            get_system_prompt().await?
-        }
-    };
-
-    // Take screenshot if enabled - uses fast in-memory encoding
-    let screenshot_base64 = if include_screenshot {
-        Some(take_screenshot_base64(app).await?)
-    } else {
-        None
-    };
+async fn list_conversations(
+    db: tauri::State<'_, Db>,
+    include_archived: Option<bool>,
+) -> Result<Vec<Conversation>, String> {
+    db.run(move |db| list_conversations_internal(db, include_archived.unwrap_or(false)))
+        .await
+}
 
-    // Get recent chat history for context
-    let history = get_chat_history_internal(10)?;
-
-    // Build messages array with system prompt
-    let mut messages: Vec<Value> = vec![json!({
-        "role": "system",
-        "content": system_prompt
-    })];
-
-    // Add past messages for context, filtered by level
-    for msg in &history {
-        let include_msg = match context_level {
-            1 => {
-                // Level 1: User + character (character's own history)
-                msg.role == "user" || msg.role == "character"
-            }
-            _ => {
-                // Level 0: User + assistant only (clean assistant mode)
-                msg.role == "user" || msg.role == "assistant"
-            }
-        };
+/// Gets the conversation new messages go to
+#[command]
+async fn get_active_conversation(db: tauri::State<'_, Db>) -> Result<Conversation, String> {
+    db.run(|db| resolve_conversation(db, None)).await
+}
 
-        if !include_msg {
-            continue;
-        }
+/// Starts a conversation and makes it the active one
+#[command]
+async fn create_conversation(
+    app: AppHandle,
+    db: tauri::State<'_, Db>,
+    title: Option<String>,
+    persona: Option<String>,
+) -> Result<Conversation, String> {
+    let title = title
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| DEFAULT_CONVERSATION_TITLE.to_string());
+    let persona = persona.filter(|p| !p.trim().is_empty());
+    let conversation = db
+        .run(move |db| {
+            create_conversation_internal(
+                db,
+                &title,
+                persona.as_deref(),
+                &chrono::Utc::now().to_rfc3339(),
+            )
+        })
+        .await?;
+    info!(
+        "[conversations] Created conversation {}: {}",
+        conversation.id, conversation.title
+    );
+    switch_conversation(app, db, conversation.id).await
+}
 
-        // Convert custom roles to "assistant" for API compatibility
-        let (role, content) = if msg.role == "character" {
-            // Character messages become assistant role for API
-            ("assistant", msg.content.clone())
-        } else {
-            (msg.role.as_str(), msg.content.clone())
-        };
+/// Makes a conversation the active one and tells the windows to reload history
+#[command]
+async fn switch_conversation(
+    app: AppHandle,
+    db: tauri::State<'_, Db>,
+    conversation_id: i64,
+) -> Result<Conversation, String> {
+    let conversation = db
+        .run(move |db| resolve_conversation(db, Some(conversation_id)))
+        .await?;
+    save_active_conversation_id(conversation.id)?;
+    let _ = app.emit("conversation-switched", &conversation);
+    Ok(conversation)
+}
 
-        messages.push(json!({
-            "role": role,
-            "content": content
-        }));
+#[command]
+async fn rename_conversation(
+    db: tauri::State<'_, Db>,
+    conversation_id: i64,
+    title: String,
+) -> Result<(), String> {
+    let title = title.trim().to_string();
+    if title.is_empty() {
+        return Err("Conversation title cannot be empty".to_string());
     }
+    db.run(move |db| rename_conversation_internal(db, conversation_id, &title))
+        .await
+}
 
-    // Add current message (with or without screenshot)
-    if let Some(ref base64) = screenshot_base64 {
-        messages.push(json!({
-            "role": "user",
-            "content": [
-                {
-                    "type": "text",
-                    "text": message.clone()
-                },
-                {
-                    "type": "image_url",
-                    "image_url": {
-                        "url": format!("data:image/jpeg;base64,{}", base64)
-                    }
-                }
-            ]
-        }));
-    } else {
-        messages.push(json!({
-            "role": "user",
-            "content": message.clone()
-        }));
-    }
+/// Sets the persona notes added to the system prompt of one conversation
+#[command]
+async fn set_conversation_persona(
+    db: tauri::State<'_, Db>,
+    conversation_id: i64,
+    persona: Option<String>,
+) -> Result<(), String> {
+    let persona = persona.filter(|p| !p.trim().is_empty());
+    db.run(move |db| set_conversation_persona_internal(db, conversation_id, persona.as_deref()))
+        .await
+}
 
-    // Call OpenRouter API for main response
-    let response = call_openrouter_chat(messages, 1000, false, context_level).await?;
+/// Archives a conversation, hiding it from the default list; pass
+/// `archived: false` to restore it
+#[command]
+async fn archive_conversation(
+    db: tauri::State<'_, Db>,
+    conversation_id: i64,
+    archived: Option<bool>,
+) -> Result<(), String> {
+    db.run(move |db| archive_conversation_internal(db, conversation_id, archived.unwrap_or(true)))
+        .await
+}
 
-    if !response.status().is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(format!("API error: {}", error_text));
-    }
+/// Deletes a conversation and everything in it. Deleting the active
+/// conversation moves the chat to the most recent remaining one.
+#[command]
+async fn delete_conversation(
+    app: AppHandle,
+    db: tauri::State<'_, Db>,
+    conversation_id: i64,
+) -> Result<(), String> {
+    db.run(move |db| delete_conversation_internal(db, conversation_id))
+        .await?;
+    info!("[conversations] Deleted conversation {}", conversation_id);
 
-    let response_json: Value = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+    if load_active_conversation_id() == Some(conversation_id) {
+        let conversation = db.run(|db| resolve_conversation(db, None)).await?;
+        let _ = app.emit("conversation-switched", &conversation);
+    }
+    Ok(())
+}
 
-    let main_response = response_json["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or("No response")
-        .to_string();
+// ============ Chat Commands ============
 
-    // Store messages and generate character comments based on level
-    let timestamp = chrono::Utc::now().to_rfc3339();
-    store_chat_message(&timestamp, "user", &message, context_level)?;
+/// Resolves the provider, endpoint and credentials for a context level
+fn resolve_provider(config: &LLMConfig, context_level: u8) -> Result<LlmProvider, String> {
+    provider_of_kind(config, config.provider_for_level(context_level))
+}
 
-    let character_comments: Option<Vec<String>> = match context_level {
-        1 => {
-            // Level 1: Save response as "character"
-            store_chat_message(&timestamp, "character", &main_response, 1)?;
-            None
+/// Resolves the URL and key for a provider from the config
+fn provider_of_kind(config: &LLMConfig, kind: ProviderKind) -> Result<LlmProvider, String> {
+    let (base_url, api_key) = match kind {
+        ProviderKind::OpenRouter => {
+            // Use built-in key if available, otherwise use user-configured key
+            let key = get_builtin_api_key()
+                .or_else(|| config.openrouter_api_key.clone())
+                .ok_or_else(|| "OpenRouter API key not configured".to_string())?;
+            (llm::OPENROUTER_BASE_URL.to_string(), Some(key))
         }
-        _ => {
-            // Level 0: Save as "assistant"
-            store_chat_message(&timestamp, "assistant", &main_response, 0)?;
-            None
+        ProviderKind::OpenAI => {
+            // The built-in key is an OpenRouter key, so only the user's own key works here
+            let key = config
+                .openai_api_key
+                .clone()
+                .ok_or_else(|| "OpenAI API key not configured".to_string())?;
+            (llm::OPENAI_BASE_URL.to_string(), Some(key))
+        }
+        ProviderKind::OpenAICompatible => {
+            let base_url = config
+                .custom_base_url
+                .clone()
+                .ok_or_else(|| "Custom base URL not configured".to_string())?;
+            (base_url, config.custom_api_key.clone())
         }
+        ProviderKind::Anthropic => {
+            let key = config
+                .anthropic_api_key
+                .clone()
+                .ok_or_else(|| "Anthropic API key not configured".to_string())?;
+            (llm::ANTHROPIC_BASE_URL.to_string(), Some(key))
+        }
+        ProviderKind::Local => (format!("{}/v1", config.local_base_url()), None),
     };
 
-    Ok(ChatResponse {
-        main_response,
-        character_comments,
+    Ok(LlmProvider {
+        kind,
+        base_url,
+        api_key,
+        routing: config.openrouter_routing.clone(),
     })
 }
 
-#[command]
-async fn send_chat_message_stream(
-    app: AppHandle,
-    message: String,
-    include_screenshot: bool,
+/// Limits that apply to requests sent to a provider. Requests paid for by the
+/// built-in key are also held to `BUILTIN_KEY_LIMITS`; local models are free.
+fn spending_limits_for(config: &LLMConfig, provider: &LlmProvider) -> SpendingLimits {
+    match provider.kind {
+        ProviderKind::Local => SpendingLimits::default(),
+        ProviderKind::OpenRouter
+            if provider.api_key.is_some() && provider.api_key == get_builtin_api_key() =>
+        {
+            config.spending_limits.stricter(&BUILTIN_KEY_LIMITS)
+        }
+        _ => config.spending_limits.clone(),
+    }
+}
+
+/// Counts a message the user sent toward the per-minute cap of its level's
+/// provider. The requests the message leads to aren't counted again.
+fn count_user_request(context_level: u8) -> Result<(), String> {
+    let config = load_llm_config()?;
+    let provider = resolve_provider(&config, context_level)?;
+    count_request(&spending_limits_for(&config, &provider))
+}
+
+/// What a chat request is for; the level's reply settings only apply to replies
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CallPurpose {
+    /// The reply the user asked for
+    Reply,
+    /// The last round of a reply that calls tools, which must answer in text
+    FinalReply,
+    /// Summaries, commentary and other requests made on the app's behalf
+    Background,
+}
+
+/// A sent chat request: the provider and model that answered, and the response
+struct LlmCall {
+    provider: LlmProvider,
+    /// Model that answered, which is a fallback if earlier models failed.
+    /// With OpenRouter server fallbacks this is the first model asked; pass
+    /// the model the response names to `served_model` for the real one.
+    model: String,
+    response: reqwest::Response,
+    fallback_hops: Vec<FallbackHop>,
+    /// Models OpenRouter may route the request through, in order; empty
+    /// unless server fallbacks are on
+    routed_models: Vec<String>,
+}
+
+/// Sends a chat request to the provider configured for the context level,
+/// retrying transient failures and then trying the level's fallback models.
+/// Returns the provider alongside the response so callers can parse it.
+async fn call_llm_chat(
+    db: &Db,
+    messages: Vec<Value>,
+    tools: Vec<ToolDefinition>,
+    max_tokens: u32,
+    stream: bool,
     context_level: u8,
-) -> Result<(), String> {
-    // Get system prompt based on level
-    let system_prompt = match context_level {
-        1 => get_dialogue_prompt().await?,
-        _ => get_system_prompt().await?,
+    purpose: CallPurpose,
+) -> Result<LlmCall, String> {
+    let config = load_llm_config()?;
+    let provider = resolve_provider(&config, context_level)?;
+
+    let mut models = vec![config.model_for_level(context_level).to_string()];
+    for model in config.fallback_models_for_level(context_level) {
+        if !models.contains(model) {
+            models.push(model.clone());
+        }
+    }
+
+    let request = ChatRequest {
+        model: models[0].clone(),
+        messages,
+        max_tokens,
+        stream,
+        tools,
+        reasoning_effort: match purpose {
+            CallPurpose::Reply | CallPurpose::FinalReply => {
+                config.reasoning_effort_for_level(context_level)
+            }
+            CallPurpose::Background => None,
+        },
+        sampling: match purpose {
+            CallPurpose::Reply | CallPurpose::FinalReply => {
+                config.generation_for_level(context_level).sampling.clone()
+            }
+            CallPurpose::Background => Default::default(),
+        },
+        fallback_models: Vec::new(),
+        forbid_tool_calls: purpose == CallPurpose::FinalReply,
     };
+    send_chat_request(db, &config, provider, &models, request).await
+}
 
-    // Take screenshot if enabled - uses fast in-memory encoding
-    let screenshot_base64 = if include_screenshot {
-        Some(take_screenshot_base64(app.clone()).await?)
+/// Sends a chat request to each model in turn until one answers or fails in
+/// a way another model can't fix, retrying transient failures on each
+async fn send_chat_request(
+    db: &Db,
+    config: &LLMConfig,
+    provider: LlmProvider,
+    models: &[String],
+    mut request: ChatRequest,
+) -> Result<LlmCall, String> {
+    let limits = spending_limits_for(config, &provider);
+    db.run(move |db| check_limits(db, &limits)).await?;
+
+    // OpenRouter can walk the fallback list itself within a single request
+    let mut routed_models = Vec::new();
+    let models = if provider.kind == ProviderKind::OpenRouter && provider.routing.server_fallbacks {
+        request.fallback_models = models.iter().skip(1).cloned().collect();
+        if models.len() > 1 {
+            routed_models = models.to_vec();
+        }
+        &models[..models.len().min(1)]
     } else {
-        None
+        models
     };
 
-    // Get recent chat history for context
-    let history = get_chat_history_internal(10)?;
-
-    // Build messages array with system prompt
-    let mut messages: Vec<Value> = vec![json!({
-        "role": "system",
-        "content": system_prompt
-    })];
-
-    // Add past messages for context, filtered by level
-    for msg in &history {
-        let include_msg = match context_level {
-            1 => {
-                // Level 1: User + character (character's own history)
-                msg.role == "user" || msg.role == "character"
+    let client = reqwest::Client::new();
+    let mut fallback_hops = Vec::new();
+    for (index, model) in models.iter().enumerate() {
+        let is_last = index + 1 == models.len();
+        request.model = model.clone();
+        let body = provider.build_body(&request);
+
+        let error = match send_with_retry(&client, &provider, &body, &config.retry_policy).await {
+            // Errors that another model can't fix go back to the caller as-is
+            Ok(response) if is_last || !should_fall_back(response.status()) => {
+                return Ok(LlmCall {
+                    provider,
+                    model: request.model,
+                    response,
+                    fallback_hops,
+                    routed_models,
+                });
             }
-            _ => {
-                // Level 0: User + assistant only (clean assistant mode)
-                msg.role == "user" || msg.role == "assistant"
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                format!("{}: {}", status, body.chars().take(300).collect::<String>())
             }
+            Err(e) if is_last => return Err(format!("API request failed: {}", e)),
+            Err(e) => e.to_string(),
         };
 
-        if !include_msg {
-            continue;
-        }
+        warn!(
+            "[llm] Model {} failed ({}), falling back to {}",
+            model,
+            error,
+            models[index + 1]
+        );
+        fallback_hops.push(FallbackHop {
+            model: model.clone(),
+            error,
+        });
+    }
 
-        // Convert custom roles to "assistant" for API compatibility
-        let (role, content) = if msg.role == "character" {
-            ("assistant", msg.content.clone())
-        } else {
-            (msg.role.as_str(), msg.content.clone())
-        };
+    Err("No model configured".to_string())
+}
 
-        messages.push(json!({
-            "role": role,
-            "content": content
-        }));
+/// The model that served a call, from the model its response names. Only
+/// OpenRouter's server fallbacks can change it; the routed models ahead of
+/// the one that served failed and are returned as hops.
+fn served_model(
+    asked: &str,
+    routed_models: &[String],
+    reported: Option<&str>,
+) -> (String, Vec<FallbackHop>) {
+    let position = reported.and_then(|reported| routed_models.iter().position(|m| m == reported));
+    match position {
+        Some(position) if position > 0 => {
+            let hops = routed_models[..position]
+                .iter()
+                .map(|model| FallbackHop {
+                    model: model.clone(),
+                    error: "Failed over by OpenRouter".to_string(),
+                })
+                .collect();
+            (routed_models[position].clone(), hops)
+        }
+        _ => (asked.to_string(), Vec::new()),
     }
+}
 
-    // Add current message (with or without screenshot)
-    if let Some(ref base64) = screenshot_base64 {
-        messages.push(json!({
-            "role": "user",
-            "content": [
-                { "type": "text", "text": message.clone() },
-                { "type": "image_url", "image_url": { "url": format!("data:image/jpeg;base64,{}", base64) } }
-            ]
-        }));
-    } else {
-        messages.push(json!({
-            "role": "user",
-            "content": message.clone()
-        }));
-    }
+/// Whether a failed response should move on to the next fallback model:
+/// the model is over capacity, unavailable or not found
+fn should_fall_back(status: reqwest::StatusCode) -> bool {
+    !status.is_success()
+        && (retry::is_retryable_status(status) || status == reqwest::StatusCode::NOT_FOUND)
+}
 
-    // Store user message
-    let timestamp = chrono::Utc::now().to_rfc3339();
-    store_chat_message(&timestamp, "user", &message, context_level)?;
+/// Sends a chat request, retrying transient failures per the retry policy.
+/// Returns the last response, successful or not, or the last send error.
+async fn send_with_retry(
+    client: &reqwest::Client,
+    provider: &LlmProvider,
+    body: &Value,
+    policy: &RetryPolicy,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut attempt = 1;
+    loop {
+        let result = provider.post_chat(client, body).send().await;
+        let (failure, retry_after) = match &result {
+            Ok(response) if retry::is_retryable_status(response.status()) => (
+                response.status().to_string(),
+                retry::retry_after(response.headers()),
+            ),
+            Err(e) if retry::is_retryable_error(e) => (e.to_string(), None),
+            _ => return result,
+        };
+        if attempt >= policy.max_attempts {
+            return result;
+        }
 
-    // Determine the role for this context level
-    let response_role = match context_level {
-        1 => "character",
-        _ => "assistant",
-    };
+        let delay = policy.delay(attempt, retry_after);
+        warn!(
+            "[llm] Attempt {} failed ({}), retrying in {} ms",
+            attempt,
+            failure,
+            delay.as_millis()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
 
-    // Call OpenRouter API with streaming
-    let response = call_openrouter_chat(messages, 1000, true, context_level).await?;
+/// Usage and timing gathered across the rounds of one reply
+struct ReplyAccounting {
+    started: Instant,
+    model: Option<String>,
+    fallback_hops: Vec<FallbackHop>,
+    usage: Option<Usage>,
+    finish_reason: Option<FinishReason>,
+}
 
-    if !response.status().is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        let _ = app.emit("chat-stream-error", json!({ "error": error_text }));
-        return Err(format!("API error: {}", error_text));
+impl ReplyAccounting {
+    fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            model: None,
+            fallback_hops: Vec::new(),
+            usage: None,
+            finish_reason: None,
+        }
     }
 
-    // Stream the response
-    let mut stream = response.bytes_stream();
-    let mut full_content = String::new();
-    let mut buffer = String::new();
-
-    while let Some(chunk_result) = stream.next().await {
-        match chunk_result {
-            Ok(chunk) => {
-                let chunk_str = String::from_utf8_lossy(&chunk);
-                buffer.push_str(&chunk_str);
+    /// Adds one round's usage; the last round decides the finish reason
+    fn add_round(&mut self, usage: Option<&Usage>, finish_reason: Option<FinishReason>) {
+        if let Some(usage) = usage {
+            self.usage.get_or_insert_with(Usage::default).add(usage);
+        }
+        self.finish_reason = finish_reason;
+    }
 
-                // Process complete SSE lines from buffer
-                while let Some(line_end) = buffer.find('\n') {
-                    let line = buffer[..line_end].trim().to_string();
-                    buffer = buffer[line_end + 1..].to_string();
+    /// Fills in the accounting columns of the reply about to be stored
+    fn apply(&self, message: NewChatMessage) -> NewChatMessage {
+        NewChatMessage {
+            model: self.model.clone(),
+            fallback_hops: self.fallback_hops.clone(),
+            usage: self.usage.clone(),
+            latency_ms: Some(self.started.elapsed().as_millis() as u64),
+            finish_reason: self.finish_reason.as_ref().map(|r| r.as_str().to_string()),
+            ..message
+        }
+    }
+}
 
-                    if line.is_empty() {
-                        continue;
-                    }
+/// Records a paid call in the usage ledger that spending limits count. A
+/// call whose provider reported no usage still counts as a request.
+async fn record_call_usage(
+    db: &Db,
+    purpose: &'static str,
+    model: &str,
+    usage: Option<&Usage>,
+    started: Instant,
+) {
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let model = model.to_string();
+    let usage = usage.cloned().unwrap_or_default();
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let recorded = db
+        .run(move |db| record_usage(db, &timestamp, purpose, &model, &usage, Some(latency_ms)))
+        .await;
+    if let Err(e) = recorded {
+        warn!("[usage] {}", e);
+    }
+}
+
+/// Most request/response rounds per message when the model keeps calling tools
+const MAX_TOOL_ROUNDS: usize = 5;
+
+/// Purpose of a reply round; the last round may not call tools, so a
+/// model that keeps calling them still ends with an answer
+fn reply_round_purpose(round: usize) -> CallPurpose {
+    if round + 1 == MAX_TOOL_ROUNDS {
+        CallPurpose::FinalReply
+    } else {
+        CallPurpose::Reply
+    }
+}
+
+/// Tools advertised at a context level; only the assistant level gets tools
+fn tools_for_level(context_level: u8) -> Vec<ToolDefinition> {
+    match context_level {
+        0 => tool_registry().definitions(),
+        _ => Vec::new(),
+    }
+}
+
+/// Runs the tool calls from one reply, storing and emitting each invocation,
+/// and appends the call and its results to `messages` for the next round
+async fn run_tool_calls(
+    app: &AppHandle,
+    conversation_id: i64,
+    request_id: &str,
+    timestamp: &str,
+    context_level: u8,
+    content: &str,
+    tool_calls: &[ToolCall],
+    messages: &mut Vec<Value>,
+) -> Result<(), String> {
+    let db = app.state::<Db>();
+    messages.push(llm::assistant_tool_call_message(content, tool_calls));
+
+    for call in tool_calls {
+        info!("[tools] Request {} calling {}", request_id, call.name);
+        let _ = app.emit(
+            "chat-tool-call",
+            json!({
+                "request_id": request_id,
+                "context_level": context_level,
+                "tool_call_id": call.id,
+                "name": call.name,
+                "arguments": call.arguments
+            }),
+        );
+
+        // Tool failures are reported to the model so it can recover
+        let (result, is_error) = match tool_registry()
+            .invoke(&db, &call.name, &call.arguments)
+            .await
+        {
+            Ok(result) => (result, false),
+            Err(e) => {
+                warn!("[tools] {} failed: {}", call.name, e);
+                (format!("Error: {}", e), true)
+            }
+        };
+
+        let _ = app.emit(
+            "chat-tool-result",
+            json!({
+                "request_id": request_id,
+                "context_level": context_level,
+                "tool_call_id": call.id,
+                "name": call.name,
+                "result": result,
+                "is_error": is_error
+            }),
+        );
+
+        let record = json!({
+            "tool_call_id": call.id,
+            "name": call.name,
+            "arguments": call.arguments,
+            "result": result,
+            "is_error": is_error
+        });
+        let row = NewChatMessage {
+            request_id: Some(request_id.to_string()),
+            ..NewChatMessage::new(
+                conversation_id,
+                timestamp,
+                "tool",
+                &record.to_string(),
+                context_level,
+            )
+        };
+        db.run(move |db| store_chat_message(db, &row)).await?;
+
+        messages.push(llm::tool_result_message(&call.id, &result));
+    }
+
+    Ok(())
+}
+
+/// Reply length requested when a level's generation settings don't set one
+const DEFAULT_MAX_TOKENS: u32 = 1000;
+
+/// How many stored messages to consider when filling the context window
+const HISTORY_SCAN_LIMIT: i64 = 200;
+
+/// Gets the context length of the model selected for a context level,
+/// preferring config overrides over the model catalog
+async fn model_context_length(config: &LLMConfig, context_level: u8) -> u32 {
+    let model = config.model_for_level(context_level);
+    if let Some(length) = config.model_context_lengths.get(model) {
+        return *length;
+    }
+
+    let provider = config.provider_for_level(context_level);
+    if provider == ProviderKind::Local {
+        return context::DEFAULT_LOCAL_CONTEXT_LENGTH;
+    }
+
+    // A failed catalog fetch falls back to the default
+    catalog_model(config, context_level)
+        .await
+        .and_then(|m| m.context_length)
+        .unwrap_or(context::DEFAULT_CONTEXT_LENGTH)
+}
+
+/// Catalog entry for the model selected at a context level
+async fn catalog_model(config: &LLMConfig, context_level: u8) -> Option<ModelOption> {
+    find_catalog_model(config.model_for_level(context_level)).await
+}
+
+/// Reply length requested from the vision relay model
+const VISION_RELAY_MAX_TOKENS: u32 = 400;
+
+/// Whether a level's screenshots go through the vision relay: a relay model
+/// is set and the level's model isn't known to accept images
+async fn uses_vision_relay(config: &LLMConfig, context_level: u8) -> bool {
+    config.vision_relay_model.is_some()
+        && !catalog_model(config, context_level)
+            .await
+            .is_some_and(|m| m.supports_vision)
+}
+
+/// Has the vision relay model describe a screenshot, with the user's message
+/// as a hint of what matters on screen
+async fn describe_screenshot(
+    db: &Db,
+    config: &LLMConfig,
+    message: &str,
+    screenshot_base64: &str,
+) -> Result<String, String> {
+    let model = config
+        .vision_relay_model
+        .clone()
+        .ok_or_else(|| "Vision relay model not configured".to_string())?;
+    let provider = provider_of_kind(config, config.vision_relay_provider)?;
+
+    let request = ChatRequest {
+        model: model.clone(),
+        messages: vec![
+            json!({ "role": "system", "content": DEFAULT_VISION_RELAY_PROMPT }),
+            json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": format!("The user's message: {}", message) },
+                    { "type": "image_url", "image_url": { "url": format!("data:image/jpeg;base64,{}", screenshot_base64) } }
+                ]
+            }),
+        ],
+        max_tokens: VISION_RELAY_MAX_TOKENS,
+        stream: false,
+        tools: Vec::new(),
+        reasoning_effort: None,
+        sampling: Default::default(),
+        fallback_models: Vec::new(),
+        forbid_tool_calls: false,
+    };
+    let started = Instant::now();
+    let LlmCall {
+        provider,
+        model,
+        response,
+        ..
+    } = send_chat_request(db, config, provider, &[model], request).await?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(format!("Vision relay error: {}", error_text));
+    }
+    let response_json: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse vision relay response: {}", e))?;
+
+    let completion = provider.parse_completion(&response_json);
+    record_call_usage(
+        db,
+        "vision_relay",
+        &model,
+        completion.usage.as_ref(),
+        started,
+    )
+    .await;
+    let description = completion.content;
+    if description.trim().is_empty() {
+        return Err("Vision relay returned an empty description".to_string());
+    }
+    Ok(description.trim().to_string())
+}
+
+/// Builds the context window for a chat request: system prompt, rolling
+/// summary, as much history as fits the token budget, and the current message
+async fn build_chat_messages(
+    app: AppHandle,
+    conversation: &Conversation,
+    message: &str,
+    include_screenshot: bool,
+    context_level: u8,
+    max_tokens: u32,
+    attachments: &[PreparedAttachment],
+) -> Result<context::ContextWindow, String> {
+    let config = load_llm_config()?;
+    let db = app.state::<Db>().inner().clone();
+
+    // Get system prompt based on level
+    let system_prompt = match context_level {
+        // Level 1: Use dialogue prompt (respond AS the character in direct conversation)
+        1 => get_dialogue_prompt().await?,
+        // Level 0: Default system prompt
+        _ => get_system_prompt().await?,
+    };
+    let mut system_prompt = render_prompt(&system_prompt, context_level)?;
+    if let Some(persona) = conversation
+        .persona
+        .as_deref()
+        .filter(|p| !p.trim().is_empty())
+    {
+        system_prompt = format!("{}\n\n{}", system_prompt, persona.trim());
+    }
+    if context_level == 1 && config.character_emotions {
+        system_prompt = format!("{}\n\n{}", system_prompt, emotions::prompt_addendum());
+    }
+
+    // Take screenshot if enabled - uses fast in-memory encoding
+    let screenshot_base64 = if include_screenshot {
+        Some(take_screenshot_base64(app).await?)
+    } else {
+        None
+    };
+
+    // Text-only models get a description of the screenshot instead of the image
+    let mut message = message.to_string();
+    let screenshot_base64 = match screenshot_base64 {
+        Some(base64) if uses_vision_relay(&config, context_level).await => {
+            match describe_screenshot(&db, &config, &message, &base64).await {
+                Ok(description) => {
+                    message = format!(
+                        "{}\n\n[What's on my screen right now: {}]",
+                        message, description
+                    );
+                }
+                // The model can't take the image either way, so carry on without it
+                Err(e) => warn!("[vision_relay] {}", e),
+            }
+            None
+        }
+        other => other,
+    };
+
+    // Current message (with or without screenshot and attachments)
+    let current_message = if screenshot_base64.is_some() || !attachments.is_empty() {
+        let mut content = vec![json!({ "type": "text", "text": message })];
+        content.extend(attachments.iter().map(|a| a.part.clone()));
+        if let Some(ref base64) = screenshot_base64 {
+            content.push(json!({ "type": "image_url", "image_url": { "url": format!("data:image/jpeg;base64,{}", base64) } }));
+        }
+        json!({
+            "role": "user",
+            "content": content
+        })
+    } else {
+        json!({
+            "role": "user",
+            "content": message
+        })
+    };
+
+    // Leave room for the reply within the model's context length
+    let context_length = model_context_length(&config, context_level).await;
+    let budget = config
+        .context_budget_tokens
+        .map_or(context_length, |b| b.min(context_length))
+        .saturating_sub(max_tokens);
+
+    let conversation_id = conversation.id;
+    let (summary, history) = db
+        .run(move |db| {
+            Ok((
+                get_conversation_summary(db, conversation_id, context_level)?,
+                get_chat_history_internal(db, Some(conversation_id), HISTORY_SCAN_LIMIT)?,
+            ))
+        })
+        .await?;
+    let window = context::build_context_window(
+        &system_prompt,
+        summary.as_ref().map(|s| s.summary.as_str()),
+        &history,
+        current_message,
+        context_level,
+        budget,
+    );
+
+    info!(
+        "[context] Level {}: {} history messages, ~{} of {} tokens",
+        context_level, window.history_included, window.estimated_tokens, budget
+    );
+
+    Ok(window)
+}
+
+/// Fewest out-of-window messages worth spending a summarization call on
+const SUMMARY_MIN_MESSAGES: usize = 6;
+
+/// Most messages folded into the summary per run
+const SUMMARY_BATCH_LIMIT: i64 = 60;
+
+/// Reply length requested for summaries
+const SUMMARY_MAX_TOKENS: u32 = 500;
+
+/// Conversations and context levels with a summarization run in flight, so
+/// turns don't pile up duplicate runs for the same summary
+static SUMMARIZERS_RUNNING: Mutex<BTreeSet<(i64, u8)>> = Mutex::new(BTreeSet::new());
+
+/// Starts a background run that folds messages older than `window_start_id`
+/// into the rolling summary of the conversation's context level
+fn spawn_summarizer(db: Db, conversation_id: i64, context_level: u8, window_start_id: i64) {
+    let key = (conversation_id, context_level);
+    let started = SUMMARIZERS_RUNNING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key);
+    if !started {
+        return;
+    }
+
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) =
+            update_conversation_summary(&db, conversation_id, context_level, window_start_id).await
+        {
+            warn!("[summary] Level {}: {}", context_level, e);
+        }
+        SUMMARIZERS_RUNNING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&key);
+    });
+}
+
+/// Condenses unsummarized messages that fell out of the context window
+async fn update_conversation_summary(
+    db: &Db,
+    conversation_id: i64,
+    context_level: u8,
+    window_start_id: i64,
+) -> Result<(), String> {
+    let (existing, pending) = db
+        .run(move |db| {
+            let existing = get_conversation_summary(db, conversation_id, context_level)?;
+            let after_id = existing.as_ref().map_or(0, |s| s.last_message_id);
+            let pending = get_chat_messages_between(
+                db,
+                conversation_id,
+                after_id,
+                window_start_id,
+                context::history_roles(context_level),
+                SUMMARY_BATCH_LIMIT,
+            )?;
+            Ok((existing, pending))
+        })
+        .await?;
+    let after_id = existing.as_ref().map_or(0, |s| s.last_message_id);
+    if pending.len() < SUMMARY_MIN_MESSAGES {
+        return Ok(());
+    }
+    let Some(last_message_id) = pending.last().and_then(|m| m.id) else {
+        return Ok(());
+    };
+
+    info!(
+        "[summary] Level {}: folding {} messages (ids {}..={}) into summary",
+        context_level,
+        pending.len(),
+        after_id,
+        last_message_id
+    );
+
+    let transcript = context::format_transcript(&pending, context_level);
+
+    let previous = existing
+        .as_ref()
+        .map_or("(none yet)", |s| s.summary.as_str());
+    let messages = vec![
+        json!({ "role": "system", "content": DEFAULT_SUMMARY_PROMPT }),
+        json!({
+            "role": "user",
+            "content": format!(
+                "Existing summary:\n{}\n\nNew turns:\n{}",
+                previous, transcript
+            )
+        }),
+    ];
+
+    let started = Instant::now();
+    let LlmCall {
+        provider,
+        model,
+        response,
+        routed_models,
+        ..
+    } = call_llm_chat(
+        db,
+        messages,
+        Vec::new(),
+        SUMMARY_MAX_TOKENS,
+        false,
+        context_level,
+        CallPurpose::Background,
+    )
+    .await?;
+    if !response.status().is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(format!("API error: {}", error_text));
+    }
+
+    let response_json: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+    let completion = provider.parse_completion(&response_json);
+    let (model, _) = served_model(&model, &routed_models, completion.model.as_deref());
+    record_call_usage(db, "summary", &model, completion.usage.as_ref(), started).await;
+    let summary = completion.content;
+    if summary.trim().is_empty() {
+        return Err("Summarizer returned an empty summary".to_string());
+    }
+
+    let summary = ConversationSummary {
+        conversation_id,
+        context_level,
+        summary: summary.trim().to_string(),
+        last_message_id,
+        updated_at: chrono::Utc::now().to_rfc3339(),
+    };
+    db.run(move |db| save_conversation_summary(db, &summary))
+        .await
+}
+
+/// Reply length requested for character commentary
+const COMMENTARY_MAX_TOKENS: u32 = 300;
+
+/// Asks the character to comment on an assistant reply, storing each comment
+/// as a `character` row linked to the reply and emitting it to the overlay
+async fn generate_character_comments(
+    app: &AppHandle,
+    conversation_id: i64,
+    request_id: &str,
+    timestamp: &str,
+    reply_id: i64,
+    reply: &str,
+) -> Result<Vec<String>, String> {
+    let messages = vec![
+        json!({ "role": "system", "content": render_prompt(&get_character_prompt().await?, 1)? }),
+        json!({ "role": "user", "content": reply }),
+    ];
+
+    // Comments are in the character's voice, so they use the character level's model
+    let db = app.state::<Db>();
+    let mut accounting = ReplyAccounting::start();
+    let LlmCall {
+        provider,
+        model,
+        response,
+        fallback_hops,
+        routed_models,
+    } = call_llm_chat(
+        &db,
+        messages,
+        Vec::new(),
+        COMMENTARY_MAX_TOKENS,
+        false,
+        1,
+        CallPurpose::Background,
+    )
+    .await?;
+    accounting.fallback_hops.extend(fallback_hops);
+
+    if !response.status().is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(format!("API error: {}", error_text));
+    }
+
+    let response_json: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+    let completion = provider.parse_completion(&response_json);
+    let (model, server_hops) = served_model(&model, &routed_models, completion.model.as_deref());
+    accounting.model = Some(model.clone());
+    accounting.fallback_hops.extend(server_hops);
+    accounting.add_round(completion.usage.as_ref(), completion.finish_reason.clone());
+    record_call_usage(
+        &db,
+        "commentary",
+        &model,
+        completion.usage.as_ref(),
+        accounting.started,
+    )
+    .await;
+
+    let comments = commentary::parse_comment_array(&completion.content)?;
+    for (index, comment) in comments.iter().enumerate() {
+        let row = NewChatMessage {
+            request_id: Some(request_id.to_string()),
+            parent_id: Some(reply_id),
+            ..NewChatMessage::new(conversation_id, timestamp, "character", comment, 0)
+        };
+        // The call's usage is recorded once, on the first comment
+        let row = if index == 0 {
+            accounting.apply(row)
+        } else {
+            row
+        };
+        let comment_id = db.run(move |db| store_chat_message(db, &row)).await?;
+
+        let _ = app.emit(
+            "chat-character-comment",
+            json!({
+                "request_id": request_id,
+                "id": comment_id,
+                "parent_id": reply_id,
+                "index": index,
+                "comment": comment,
+                "context_level": 0
+            }),
+        );
+    }
+
+    Ok(comments)
+}
+
+/// Emotion mapping for replies at a context level, or `None` when its
+/// replies carry no emotion markers
+fn emotion_map_for_level(context_level: u8) -> Result<Option<EmotionMap>, String> {
+    if context_level != 1 || !load_llm_config()?.character_emotions {
+        return Ok(None);
+    }
+    // Markers are still stripped when the model's mapping can't be read
+    Ok(Some(load_emotion_map().unwrap_or_else(|e| {
+        warn!("[emotions] {}", e);
+        EmotionMap::new()
+    })))
+}
+
+/// Tells the overlay which expression and motion to play for an emotion
+fn emit_character_emotion(app: &AppHandle, request_id: &str, mapping: &EmotionMap, emotion: &str) {
+    let action = mapping.get(emotion).cloned().unwrap_or_default();
+    let _ = app.emit(
+        "character-emotion",
+        json!({
+            "request_id": request_id,
+            "emotion": emotion,
+            "expression": action.expression,
+            "motion": action.motion
+        }),
+    );
+}
+
+/// Stores the user's message, then keeps a copy of each attachment linked
+/// to it, returning the message's row id
+async fn store_user_message(
+    db: &Db,
+    message: NewChatMessage,
+    attachments: &[PreparedAttachment],
+) -> Result<i64, String> {
+    let attachments: Vec<(PathBuf, MessageAttachment)> = attachments
+        .iter()
+        .map(|attachment| {
+            let row = MessageAttachment {
+                id: 0,
+                message_id: 0,
+                file_name: attachment.file_name.clone(),
+                stored_path: String::new(),
+                mime_type: attachment.mime_type.clone(),
+                kind: attachment.kind.as_str().to_string(),
+                size_bytes: attachment.size_bytes as i64,
+                created_at: message.timestamp.clone(),
+            };
+            (attachment.source_path.clone(), row)
+        })
+        .collect();
 
-                    if line == "data: [DONE]" {
-                        break;
+    db.run(move |db| {
+        let message_id = store_chat_message(db, &message)?;
+        let mut rows = Vec::with_capacity(attachments.len());
+        for (index, (source_path, mut row)) in attachments.into_iter().enumerate() {
+            // The content already went into the request, so a failed copy
+            // only loses the archived file
+            let stored_path = match store_copy(&source_path, message_id, index, &row.file_name) {
+                Ok(stored_path) => stored_path,
+                Err(e) => {
+                    warn!("[attachments] {}", e);
+                    continue;
+                }
+            };
+            row.message_id = message_id;
+            row.stored_path = stored_path.to_string_lossy().into_owned();
+            rows.push(row);
+        }
+        if let Err(e) = store_message_attachments(db, &rows) {
+            // No row points at the copies, so they would never be cleaned up
+            let stored_paths: Vec<String> = rows.into_iter().map(|r| r.stored_path).collect();
+            remove_stored_copies(&stored_paths);
+            return Err(e);
+        }
+        Ok(message_id)
+    })
+    .await
+}
+
+#[command]
+async fn send_chat_message(
+    app: AppHandle,
+    db: tauri::State<'_, Db>,
+    message: String,
+    include_screenshot: bool,
+    context_level: u8,
+    attachments: Option<Vec<String>>,
+    conversation_id: Option<i64>,
+) -> Result<ChatResponse, String> {
+    let request_id = generate_request_id();
+    count_user_request(context_level)?;
+    let conversation = db
+        .run(move |db| resolve_conversation(db, conversation_id))
+        .await?;
+    let conversation_id = conversation.id;
+    let attachments = prepare_attachments(attachments.unwrap_or_default()).await?;
+
+    let max_tokens = load_llm_config()?.max_tokens_for_level(context_level);
+    let window = build_chat_messages(
+        app.clone(),
+        &conversation,
+        &message,
+        include_screenshot,
+        context_level,
+        max_tokens,
+        &attachments,
+    )
+    .await?;
+
+    // Store the user message first so tool rows follow it in history
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let user_message_id = store_user_message(
+        &db,
+        NewChatMessage {
+            request_id: Some(request_id.clone()),
+            ..NewChatMessage::new(conversation_id, &timestamp, "user", &message, context_level)
+        },
+        &attachments,
+    )
+    .await?;
+
+    let tools = tools_for_level(context_level);
+    let mut messages = window.messages;
+    let mut main_response = String::new();
+    let mut reasoning = String::new();
+    let mut accounting = ReplyAccounting::start();
+
+    for round in 0..MAX_TOOL_ROUNDS {
+        // Call the configured provider for main response
+        let round_started = Instant::now();
+        let LlmCall {
+            provider,
+            model,
+            response,
+            fallback_hops,
+            routed_models,
+        } = call_llm_chat(
+            &db,
+            messages.clone(),
+            tools.clone(),
+            max_tokens,
+            false,
+            context_level,
+            reply_round_purpose(round),
+        )
+        .await?;
+        accounting.fallback_hops.extend(fallback_hops);
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(format!("API error: {}", error_text));
+        }
+
+        let response_json: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+        let completion = provider.parse_completion(&response_json);
+        let (model, server_hops) =
+            served_model(&model, &routed_models, completion.model.as_deref());
+        accounting.model = Some(model.clone());
+        accounting.fallback_hops.extend(server_hops);
+        main_response.push_str(&completion.content);
+        reasoning.push_str(&completion.reasoning);
+        accounting.add_round(completion.usage.as_ref(), completion.finish_reason.clone());
+        record_call_usage(
+            &db,
+            "reply",
+            &model,
+            completion.usage.as_ref(),
+            round_started,
+        )
+        .await;
+
+        if completion.tool_calls.is_empty() {
+            break;
+        }
+        if round + 1 == MAX_TOOL_ROUNDS {
+            warn!(
+                "[tools] Request {} called tools on its final round",
+                request_id
+            );
+            break;
+        }
+
+        run_tool_calls(
+            &app,
+            conversation_id,
+            &request_id,
+            &timestamp,
+            context_level,
+            &completion.content,
+            &completion.tool_calls,
+            &mut messages,
+        )
+        .await?;
+    }
+
+    if let Some(mapping) = emotion_map_for_level(context_level)? {
+        let (text, emotions) = emotions::strip_emotions(&main_response);
+        for emotion in emotions {
+            emit_character_emotion(&app, &request_id, &mapping, emotion);
+        }
+        main_response = text;
+    }
+
+    if main_response.is_empty() {
+        main_response = "No response".to_string();
+    }
+
+    // Store the response and generate character comments based on level
+    let character_comments: Option<Vec<String>> = match context_level {
+        1 => {
+            // Level 1: Save response as "character"
+            let row = accounting.apply(NewChatMessage {
+                request_id: Some(request_id.clone()),
+                reasoning: Some(reasoning).filter(|r| !r.is_empty()),
+                ..NewChatMessage::new(conversation_id, &timestamp, "character", &main_response, 1)
+            });
+            db.run(move |db| store_chat_message(db, &row)).await?;
+            None
+        }
+        _ => {
+            // Level 0: Save as "assistant", then let the character comment on it
+            let row = accounting.apply(NewChatMessage {
+                request_id: Some(request_id.clone()),
+                reasoning: Some(reasoning).filter(|r| !r.is_empty()),
+                ..NewChatMessage::new(conversation_id, &timestamp, "assistant", &main_response, 0)
+            });
+            let reply_id = db.run(move |db| store_chat_message(db, &row)).await?;
+
+            if load_llm_config()?.character_commentary {
+                match generate_character_comments(
+                    &app,
+                    conversation_id,
+                    &request_id,
+                    &timestamp,
+                    reply_id,
+                    &main_response,
+                )
+                .await
+                {
+                    Ok(comments) => Some(comments),
+                    Err(e) => {
+                        warn!("[commentary] Request {}: {}", request_id, e);
+                        None
                     }
+                }
+            } else {
+                None
+            }
+        }
+    };
 
-                    if let Some(json_str) = line.strip_prefix("data: ") {
-                        if let Ok(json_value) = serde_json::from_str::<Value>(json_str) {
-                            if let Some(content) =
-                                json_value["choices"][0]["delta"]["content"].as_str()
-                            {
-                                full_content.push_str(content);
-                                let _ = app.emit(
-                                    "chat-stream-chunk",
-                                    json!({
-                                        "chunk": content,
-                                        "role": response_role,
-                                        "context_level": context_level
-                                    }),
-                                );
+    spawn_summarizer(
+        db.inner().clone(),
+        conversation_id,
+        context_level,
+        window.oldest_included_id.unwrap_or(user_message_id),
+    );
+
+    Ok(ChatResponse {
+        main_response,
+        character_comments,
+        request_id,
+    })
+}
+
+#[command]
+#[allow(clippy::too_many_arguments)]
+async fn send_chat_message_stream(
+    app: AppHandle,
+    state: tauri::State<'_, AppState>,
+    db: tauri::State<'_, Db>,
+    message: String,
+    include_screenshot: bool,
+    context_level: u8,
+    request_id: Option<String>,
+    attachments: Option<Vec<String>>,
+    conversation_id: Option<i64>,
+) -> Result<String, String> {
+    let request_id = request_id.unwrap_or_else(generate_request_id);
+    count_user_request(context_level)?;
+    let conversation = db
+        .run(move |db| resolve_conversation(db, conversation_id))
+        .await?;
+    let conversation_id = conversation.id;
+    let attachments = prepare_attachments(attachments.unwrap_or_default()).await?;
+
+    // Register the stream so cancel_chat_stream can abort it at any point
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    let _registration = ActiveStreamGuard::register(&state, &request_id, abort_handle);
+
+    let max_tokens = load_llm_config()?.max_tokens_for_level(context_level);
+    let window = build_chat_messages(
+        app.clone(),
+        &conversation,
+        &message,
+        include_screenshot,
+        context_level,
+        max_tokens,
+        &attachments,
+    )
+    .await?;
+
+    // Store user message
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let user_message_id = store_user_message(
+        &db,
+        NewChatMessage {
+            request_id: Some(request_id.clone()),
+            ..NewChatMessage::new(conversation_id, &timestamp, "user", &message, context_level)
+        },
+        &attachments,
+    )
+    .await?;
+
+    // Determine the role for this context level
+    let response_role = match context_level {
+        1 => "character",
+        _ => "assistant",
+    };
+
+    let _ = app.emit(
+        "chat-stream-start",
+        json!({
+            "request_id": request_id,
+            "role": response_role,
+            "context_level": context_level,
+            "conversation_id": conversation_id
+        }),
+    );
+
+    // Request and stream the response. Aborting drops the future, which closes
+    // the HTTP connection; the text received so far stays in full_content.
+    let mut full_content = String::new();
+    let mut full_reasoning = String::new();
+    let mut accounting = ReplyAccounting::start();
+    let emotion_map = emotion_map_for_level(context_level)?;
+    let mut emotion_stripper = EmotionStripper::default();
+    let stream_result = Abortable::new(
+        async {
+            let tools = tools_for_level(context_level);
+            let mut messages = window.messages;
+            let emit_error = |error: &str| {
+                let _ = app.emit(
+                    "chat-stream-error",
+                    json!({
+                        "request_id": request_id,
+                        "role": response_role,
+                        "context_level": context_level,
+                        "error": error
+                    }),
+                );
+            };
+
+            // Each round streams one reply; replies that call tools get the
+            // results appended and go around again
+            for round in 0..MAX_TOOL_ROUNDS {
+                // Call the configured provider with streaming
+                let round_started = Instant::now();
+                let LlmCall {
+                    provider,
+                    model,
+                    response,
+                    fallback_hops,
+                    routed_models,
+                } = call_llm_chat(
+                    &db,
+                    messages.clone(),
+                    tools.clone(),
+                    max_tokens,
+                    true,
+                    context_level,
+                    reply_round_purpose(round),
+                )
+                .await?;
+                accounting.model = Some(model.clone());
+                accounting.fallback_hops.extend(fallback_hops);
+
+                if !response.status().is_success() {
+                    let error_text = response.text().await.unwrap_or_default();
+                    emit_error(&error_text);
+                    return Err(format!("API error: {}", error_text));
+                }
+
+                // Stream the response
+                let mut stream = response.bytes_stream();
+                let mut decoder = SseDecoder::default();
+                let mut round_content = String::new();
+                let mut tool_calls = ToolCallAccumulator::default();
+                let mut round_usage: Option<Usage> = None;
+                let mut round_finish_reason = None;
+                let mut reported_model: Option<String> = None;
+                let mut done = false;
+
+                while !done {
+                    let events = match stream.next().await {
+                        Some(Ok(chunk)) => decoder.push(&chunk),
+                        Some(Err(e)) => {
+                            emit_error(&e.to_string());
+                            return Err(format!("Stream error: {}", e));
+                        }
+                        // The body ended; flush any event left without a trailing blank line
+                        None => {
+                            done = true;
+                            decoder.finish()
+                        }
+                    };
+
+                    for event in events {
+                        let json_value = match streaming::interpret(&event) {
+                            Some(ChatStreamEvent::Data(json_value)) => json_value,
+                            Some(ChatStreamEvent::Done) => {
+                                done = true;
+                                break;
+                            }
+                            Some(ChatStreamEvent::Error(message)) => {
+                                emit_error(&message);
+                                return Err(format!("Stream error: {}", message));
+                            }
+                            None => continue,
+                        };
+
+                        for delta in provider.parse_stream_event(&json_value) {
+                            match delta {
+                                StreamDelta::Text(content) => {
+                                    let content = match &emotion_map {
+                                        Some(mapping) => {
+                                            let (text, emotions) = emotion_stripper.push(&content);
+                                            for emotion in emotions {
+                                                emit_character_emotion(
+                                                    &app,
+                                                    &request_id,
+                                                    mapping,
+                                                    emotion,
+                                                );
+                                            }
+                                            text
+                                        }
+                                        None => content,
+                                    };
+                                    if content.is_empty() {
+                                        continue;
+                                    }
+                                    full_content.push_str(&content);
+                                    round_content.push_str(&content);
+                                    let _ = app.emit(
+                                        "chat-stream-chunk",
+                                        json!({
+                                            "request_id": request_id,
+                                            "chunk": content,
+                                            "role": response_role,
+                                            "context_level": context_level
+                                        }),
+                                    );
+                                }
+                                // Reasoning goes to its own event and column so it
+                                // never mixes into the answer or later prompts
+                                StreamDelta::Reasoning(chunk) => {
+                                    full_reasoning.push_str(&chunk);
+                                    let _ = app.emit(
+                                        "chat-stream-reasoning",
+                                        json!({
+                                            "request_id": request_id,
+                                            "chunk": chunk,
+                                            "role": response_role,
+                                            "context_level": context_level
+                                        }),
+                                    );
+                                }
+                                StreamDelta::ToolCall {
+                                    index,
+                                    id,
+                                    name,
+                                    arguments,
+                                } => tool_calls.push(index, id, name, &arguments),
+                                StreamDelta::Usage(usage) => {
+                                    round_usage.get_or_insert_with(Usage::default).merge(&usage)
+                                }
+                                StreamDelta::Finish(reason) => round_finish_reason = Some(reason),
+                                StreamDelta::Model(model) => {
+                                    reported_model.get_or_insert(model);
+                                }
                             }
                         }
                     }
                 }
+
+                // A body that ends without a finish reason was cut off
+                let round_finish_reason = round_finish_reason.unwrap_or_else(|| {
+                    warn!(
+                        "[send_chat_message_stream] Request {} ended without a finish reason",
+                        request_id
+                    );
+                    FinishReason::Incomplete
+                });
+                let (model, server_hops) =
+                    served_model(&model, &routed_models, reported_model.as_deref());
+                accounting.model = Some(model.clone());
+                accounting.fallback_hops.extend(server_hops);
+                accounting.add_round(round_usage.as_ref(), Some(round_finish_reason));
+                record_call_usage(&db, "reply", &model, round_usage.as_ref(), round_started).await;
+
+                if tool_calls.is_empty() {
+                    break;
+                }
+                if round + 1 == MAX_TOOL_ROUNDS {
+                    warn!(
+                        "[tools] Request {} called tools on its final round",
+                        request_id
+                    );
+                    break;
+                }
+
+                run_tool_calls(
+                    &app,
+                    conversation_id,
+                    &request_id,
+                    &timestamp,
+                    context_level,
+                    &round_content,
+                    &tool_calls.finish(),
+                    &mut messages,
+                )
+                .await?;
             }
-            Err(e) => {
-                let _ = app.emit("chat-stream-error", json!({ "error": e.to_string() }));
-                return Err(format!("Stream error: {}", e));
+
+            Ok::<(), String>(())
+        },
+        abort_registration,
+    )
+    .await;
+
+    // Text held back as a possible emotion marker turned out to be plain text
+    let tail = emotion_stripper.finish();
+    if !tail.is_empty() {
+        full_content.push_str(&tail);
+        let _ = app.emit(
+            "chat-stream-chunk",
+            json!({
+                "request_id": request_id,
+                "chunk": tail,
+                "role": response_role,
+                "context_level": context_level
+            }),
+        );
+    }
+
+    match stream_result {
+        Ok(result) => result?,
+        Err(Aborted) => {
+            info!(
+                "[send_chat_message_stream] Request {} cancelled after {} chars",
+                request_id,
+                full_content.len()
+            );
+
+            // Keep the partial reply, marked so it can be told apart from
+            // complete ones; a reply cancelled before any text leaves no row
+            if !full_content.is_empty() {
+                let row = accounting.apply(NewChatMessage {
+                    cancelled: true,
+                    request_id: Some(request_id.clone()),
+                    reasoning: Some(full_reasoning).filter(|r| !r.is_empty()),
+                    ..NewChatMessage::new(
+                        conversation_id,
+                        &timestamp,
+                        response_role,
+                        &full_content,
+                        context_level,
+                    )
+                });
+                db.run(move |db| store_chat_message(db, &row)).await?;
             }
+
+            let _ = app.emit(
+                "chat-stream-cancelled",
+                json!({
+                    "request_id": request_id,
+                    "role": response_role,
+                    "context_level": context_level,
+                    "partial_content": full_content
+                }),
+            );
+
+            return Ok(request_id);
         }
     }
 
     // Store the complete response
-    store_chat_message(&timestamp, response_role, &full_content, context_level)?;
+    let row = accounting.apply(NewChatMessage {
+        request_id: Some(request_id.clone()),
+        reasoning: Some(full_reasoning).filter(|r| !r.is_empty()),
+        ..NewChatMessage::new(
+            conversation_id,
+            &timestamp,
+            response_role,
+            &full_content,
+            context_level,
+        )
+    });
+    let reply_id = db.run(move |db| store_chat_message(db, &row)).await?;
 
     // Emit completion event
     let _ = app.emit(
         "chat-stream-done",
         json!({
+            "request_id": request_id,
             "role": response_role,
             "context_level": context_level,
-            "full_content": full_content.clone()
+            "full_content": full_content.clone(),
+            "usage": accounting.usage
         }),
     );
 
-    Ok(())
+    spawn_summarizer(
+        db.inner().clone(),
+        conversation_id,
+        context_level,
+        window.oldest_included_id.unwrap_or(user_message_id),
+    );
+
+    // Character commentary arrives after the reply as separate events
+    if context_level == 0
+        && load_llm_config()?.character_commentary
+        && !full_content.trim().is_empty()
+    {
+        let request_id = request_id.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = generate_character_comments(
+                &app,
+                conversation_id,
+                &request_id,
+                &timestamp,
+                reply_id,
+                &full_content,
+            )
+            .await
+            {
+                warn!("[commentary] Request {}: {}", request_id, e);
+            }
+        });
+    }
+
+    Ok(request_id)
+}
+
+#[command]
+async fn cancel_chat_stream(
+    state: tauri::State<'_, AppState>,
+    request_id: String,
+) -> Result<bool, String> {
+    match state.active_streams.lock().unwrap().get(&request_id) {
+        Some(handle) => {
+            info!("[cancel_chat_stream] Cancelling request {}", request_id);
+            handle.abort();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Monotonic counter that keeps request ids unique within one millisecond
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a unique id for a chat request
+fn generate_request_id() -> String {
+    format!(
+        "req-{}-{}",
+        chrono::Utc::now().timestamp_millis(),
+        REQUEST_COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+/// Keeps a stream's abort handle registered for as long as the command runs
+struct ActiveStreamGuard<'a> {
+    streams: &'a Mutex<HashMap<String, AbortHandle>>,
+    request_id: String,
+}
+
+impl<'a> ActiveStreamGuard<'a> {
+    fn register(state: &'a AppState, request_id: &str, handle: AbortHandle) -> Self {
+        state
+            .active_streams
+            .lock()
+            .unwrap()
+            .insert(request_id.to_string(), handle);
+        Self {
+            streams: &state.active_streams,
+            request_id: request_id.to_string(),
+        }
+    }
+}
+
+impl Drop for ActiveStreamGuard<'_> {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.request_id);
+    }
 }
 
 // Database helper functions (store_chat_message, get_chat_history_internal) are in db.rs
 
+/// Gets the latest messages of a conversation, by default the active one
+#[command]
+async fn get_chat_history(
+    db: tauri::State<'_, Db>,
+    conversation_id: Option<i64>,
+) -> Result<Vec<ChatMessage>, String> {
+    db.run(move |db| {
+        let conversation = resolve_conversation(db, conversation_id)?;
+        get_chat_history_internal(db, Some(conversation.id), 100)
+    })
+    .await
+}
+
+/// Most messages one history page or neighbourhood can hold
+const MAX_HISTORY_PAGE: u32 = 200;
+
+/// Gets the page of messages before `before_id`, or the latest page when
+/// unset, for scrolling back through history. Without a conversation in
+/// `filters`, pages through the active conversation.
 #[command]
-async fn get_chat_history() -> Result<Vec<ChatMessage>, String> {
-    get_chat_history_internal(100)
+async fn get_chat_history_page(
+    db: tauri::State<'_, Db>,
+    before_id: Option<i64>,
+    limit: Option<u32>,
+    filters: Option<HistoryFilters>,
+) -> Result<HistoryPage, String> {
+    let mut filters = filters.unwrap_or_default();
+    let limit = limit.unwrap_or(50).clamp(1, MAX_HISTORY_PAGE);
+    db.run(move |db| {
+        if filters.conversation_id.is_none() {
+            filters.conversation_id = Some(resolve_conversation(db, None)?.id);
+        }
+        get_chat_history_page_internal(db, before_id, limit as i64, &filters)
+    })
+    .await
 }
 
+/// Gets a single chat message, e.g. a search hit
 #[command]
-async fn clear_chat_history() -> Result<(), String> {
-    clear_chat_history_internal()
+async fn get_message(
+    db: tauri::State<'_, Db>,
+    message_id: i64,
+) -> Result<Option<ChatMessage>, String> {
+    db.run(move |db| get_message_internal(db, message_id)).await
 }
 
+/// Gets a message with up to `count` messages before and after it in its
+/// conversation, for jumping to a search hit
 #[command]
-async fn clear_all_data() -> Result<(), String> {
-    clear_app_data()
+async fn get_messages_around(
+    db: tauri::State<'_, Db>,
+    message_id: i64,
+    count: Option<u32>,
+) -> Result<Vec<ChatMessage>, String> {
+    let count = count.unwrap_or(10).min(MAX_HISTORY_PAGE / 2);
+    db.run(move |db| get_messages_around_internal(db, message_id, count as i64))
+        .await
+}
+
+/// Gets the files attached to a chat message
+#[command]
+async fn get_message_attachments(
+    db: tauri::State<'_, Db>,
+    message_id: i64,
+) -> Result<Vec<MessageAttachment>, String> {
+    db.run(move |db| get_message_attachments_internal(db, message_id))
+        .await
+}
+
+/// Gets token and cost totals per day and model between two YYYY-MM-DD dates (inclusive)
+#[command]
+async fn get_usage_report(
+    db: tauri::State<'_, Db>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<Vec<UsageReportRow>, String> {
+    db.run(move |db| get_usage_report_internal(db, from.as_deref(), to.as_deref()))
+        .await
+}
+
+/// Most results a history search returns
+const MAX_SEARCH_RESULTS: u32 = 200;
+
+/// Full-text search over the stored messages of every conversation, best
+/// matches first. Dates are local YYYY-MM-DD days, inclusive.
+#[command]
+#[allow(clippy::too_many_arguments)]
+async fn search_chat_history(
+    db: tauri::State<'_, Db>,
+    query: String,
+    role: Option<String>,
+    context_level: Option<u8>,
+    from: Option<String>,
+    to: Option<String>,
+    conversation_id: Option<i64>,
+    limit: Option<u32>,
+) -> Result<Vec<ChatSearchHit>, String> {
+    let filters = HistoryFilters {
+        conversation_id,
+        role,
+        context_level,
+        from,
+        to,
+    };
+    let limit = limit.unwrap_or(50).clamp(1, MAX_SEARCH_RESULTS);
+    db.run(move |db| search_chat_history_internal(db, &query, &filters, limit as i64))
+        .await
+}
+
+/// Clears the history of a conversation, by default the active one
+#[command]
+async fn clear_chat_history(
+    db: tauri::State<'_, Db>,
+    conversation_id: Option<i64>,
+) -> Result<(), String> {
+    db.run(move |db| {
+        let conversation = resolve_conversation(db, conversation_id)?;
+        clear_chat_history_internal(db, Some(conversation.id))
+    })
+    .await
+}
+
+#[command]
+async fn clear_all_data(db: tauri::State<'_, Db>) -> Result<(), String> {
+    db.run(|db| {
+        db.close();
+        clear_app_data()
+    })
+    .await
 }
 
 #[command]
@@ -1549,6 +3766,8 @@ async fn reload_character(
 pub struct AppState {
     pub overlay_visible: Mutex<bool>,
     pub toggle_menu_item: Mutex<Option<MenuItem<tauri::Wry>>>,
+    /// Abort handles for in-flight chat streams, keyed by request id
+    pub active_streams: Mutex<HashMap<String, AbortHandle>>,
 }
 
 // ============ Overlay Window Commands ============
@@ -2537,6 +4756,7 @@ fn main() {
 
     builder
         .manage(AppState::default())
+        .manage(Db::default())
         .setup(|app| {
             // Log startup information
             info!("=== OTO Desktop Starting ===");
@@ -2599,6 +4819,7 @@ fn main() {
                             });
                         }
                         "clear_data" => {
+                            _app.state::<Db>().close();
                             if let Err(e) = clear_app_data() {
                                 error!("Error clearing app data: {}", e);
                             }
@@ -2719,9 +4940,29 @@ fn main() {
             save_openai_key,
             get_openai_key,
             has_openai_key,
+            save_anthropic_key,
+            has_anthropic_key,
+            set_custom_endpoint,
+            set_local_base_url,
             get_llm_config_cmd,
             set_model,
+            set_provider,
+            set_reasoning_effort,
+            set_generation_settings,
+            set_context_budget,
+            set_model_context_length,
+            set_spending_limits,
+            set_character_commentary,
+            set_character_emotions,
+            get_emotion_mapping,
+            save_emotion_mapping,
+            set_user_name,
+            set_vision_relay,
+            set_openrouter_routing,
+            set_fallback_models,
             get_model_supports_vision,
+            search_models,
+            set_model_favorite,
             get_available_models,
             save_system_prompt,
             get_system_prompt,
@@ -2729,10 +4970,28 @@ fn main() {
             get_character_prompt,
             save_dialogue_prompt,
             get_dialogue_prompt,
+            list_prompt_snippets,
+            save_prompt_snippet,
+            render_prompt_preview,
             send_chat_message,
             send_chat_message_stream,
+            cancel_chat_stream,
             get_chat_history,
+            get_chat_history_page,
+            get_message,
+            get_messages_around,
+            get_message_attachments,
+            get_usage_report,
             clear_chat_history,
+            search_chat_history,
+            list_conversations,
+            get_active_conversation,
+            create_conversation,
+            switch_conversation,
+            rename_conversation,
+            set_conversation_persona,
+            archive_conversation,
+            delete_conversation,
             clear_all_data,
             reload_character,
             save_hitbox,
@@ -0,0 +1,558 @@
+//! Versioned schema migrations for the chat database
+//!
+//! The database's `PRAGMA user_version` holds the number of migrations
+//! applied. Pending migrations each run in their own transaction after the
+//! file has been backed up, so a failed upgrade leaves the database at the
+//! last good version. Databases written by a newer app are refused rather
+//! than guessed at.
+//!
+//! Databases from before versioning report version 0 but may already have
+//! some of the tables and columns, so the early migrations check for what
+//! exists instead of assuming an empty file.
+
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::path::Path;
+
+type Migration = fn(&Transaction) -> Result<(), String>;
+
+/// Every schema change in order; append new ones, never edit released ones
+const MIGRATIONS: &[Migration] = &[
+    create_chat_history,
+    create_message_attachments,
+    add_conversations,
+    add_search_index,
+    add_usage_ledger,
+];
+
+/// Schema version this build writes
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Brings the database up to `SCHEMA_VERSION`, backing up its file first
+pub fn migrate(conn: &mut Connection) -> Result<(), String> {
+    run_migrations(conn, MIGRATIONS)
+}
+
+fn run_migrations(conn: &mut Connection, migrations: &[Migration]) -> Result<(), String> {
+    let latest = migrations.len() as i64;
+    let version: i64 = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(|e| format!("Failed to read database version: {}", e))?;
+
+    if version > latest {
+        return Err(format!(
+            "The chat database was created by a newer version of the app (schema {}, this version supports up to {}). Please update the app.",
+            version, latest
+        ));
+    }
+    if version == latest {
+        return Ok(());
+    }
+
+    // A brand new file, or one only in memory, has nothing worth keeping
+    let has_tables: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table'",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(|e| format!("Failed to inspect database: {}", e))?;
+    if has_tables {
+        if let Some(db_path) = conn.path().filter(|p| !p.is_empty()) {
+            backup(conn, Path::new(db_path), version)?;
+        }
+    }
+
+    for (index, migration) in migrations.iter().enumerate().skip(version as usize) {
+        let target = index as i64 + 1;
+        let tx = conn
+            .transaction()
+            .map_err(|e| format!("Failed to start migration {}: {}", target, e))?;
+        migration(&tx).map_err(|e| format!("Migration {} failed: {}", target, e))?;
+        tx.pragma_update(None, "user_version", target)
+            .map_err(|e| format!("Failed to record migration {}: {}", target, e))?;
+        tx.commit()
+            .map_err(|e| format!("Failed to commit migration {}: {}", target, e))?;
+        info!("[db] Migrated chat database to schema {}", target);
+    }
+
+    Ok(())
+}
+
+/// Writes a consistent copy of the database next to it, named after the
+/// version it is upgraded from
+fn backup(conn: &Connection, db_path: &Path, version: i64) -> Result<(), String> {
+    let stem = db_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("chat_history");
+    let backup_path = db_path.with_file_name(format!(
+        "{}.v{}.{}.bak",
+        stem,
+        version,
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+    ));
+
+    conn.execute("VACUUM INTO ?1", params![backup_path.to_string_lossy()])
+        .map_err(|e| format!("Failed to back up database before upgrading: {}", e))?;
+    info!("[db] Backed up chat database to {}", backup_path.display());
+    Ok(())
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> Result<bool, String> {
+    tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )
+    .map_err(|e| format!("Failed to inspect {}: {}", table, e))
+}
+
+/// Adds a column unless a pre-versioning database already has it
+fn add_column_if_missing(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), String> {
+    if has_column(tx, table, column)? {
+        return Ok(());
+    }
+    tx.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+        [],
+    )
+    .map_err(|e| format!("Failed to add {}.{}: {}", table, column, e))?;
+    Ok(())
+}
+
+/// 1: Messages with usage accounting, and rolling summaries per context level
+fn create_chat_history(tx: &Transaction) -> Result<(), String> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS chat_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            role TEXT NOT NULL,
+            content TEXT NOT NULL
+        )",
+        [],
+    )
+    .map_err(|e| format!("Failed to create table: {}", e))?;
+
+    // Columns that pre-versioning builds added one release at a time
+    for (column, definition) in [
+        ("context_level", "INTEGER DEFAULT 0"),
+        // Streams stopped by the user
+        ("cancelled", "INTEGER DEFAULT 0"),
+        // Correlates prompts with their replies
+        ("request_id", "TEXT"),
+        ("model", "TEXT"),
+        ("prompt_tokens", "INTEGER"),
+        ("completion_tokens", "INTEGER"),
+        ("cost", "REAL"),
+        ("latency_ms", "INTEGER"),
+        ("finish_reason", "TEXT"),
+        // Links character comments to their reply
+        ("parent_id", "INTEGER"),
+        // Model thinking kept out of the content
+        ("reasoning", "TEXT"),
+        // JSON list of models that failed before the one that replied
+        ("fallback_hops", "TEXT"),
+    ] {
+        add_column_if_missing(tx, "chat_history", column, definition)?;
+    }
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS conversation_summaries (
+            context_level INTEGER PRIMARY KEY,
+            summary TEXT NOT NULL,
+            last_message_id INTEGER NOT NULL,
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )
+    .map_err(|e| format!("Failed to create summaries table: {}", e))?;
+    Ok(())
+}
+
+/// 2: Files attached to messages
+fn create_message_attachments(tx: &Transaction) -> Result<(), String> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS message_attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL,
+            file_name TEXT NOT NULL,
+            stored_path TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )
+    .map_err(|e| format!("Failed to create attachments table: {}", e))?;
+    Ok(())
+}
+
+/// 3: Conversations. Existing history moves into a "Chat" conversation along
+/// with the rolling summaries, which become per conversation.
+fn add_conversations(tx: &Transaction) -> Result<(), String> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS conversations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL,
+            persona TEXT,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            archived INTEGER DEFAULT 0
+        )",
+        [],
+    )
+    .map_err(|e| format!("Failed to create conversations table: {}", e))?;
+    add_column_if_missing(
+        tx,
+        "chat_history",
+        "conversation_id",
+        "INTEGER REFERENCES conversations(id)",
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_chat_history_conversation ON chat_history (conversation_id, id)",
+        [],
+    )
+    .map_err(|e| format!("Failed to create conversation index: {}", e))?;
+
+    let legacy_span: Option<(String, String)> = tx
+        .query_row(
+            "SELECT MIN(timestamp), MAX(timestamp) FROM chat_history WHERE conversation_id IS NULL HAVING COUNT(*) > 0",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|e| format!("Failed to inspect chat history: {}", e))?;
+    let legacy_id = match legacy_span {
+        Some((first, last)) => {
+            tx.execute(
+                "INSERT INTO conversations (title, created_at, updated_at) VALUES ('Chat', ?1, ?2)",
+                params![first, last],
+            )
+            .map_err(|e| format!("Failed to create conversation: {}", e))?;
+            let id = tx.last_insert_rowid();
+            tx.execute(
+                "UPDATE chat_history SET conversation_id = ?1 WHERE conversation_id IS NULL",
+                params![id],
+            )
+            .map_err(|e| format!("Failed to assign messages to conversation: {}", e))?;
+            Some(id)
+        }
+        None => None,
+    };
+
+    if has_column(tx, "conversation_summaries", "conversation_id")? {
+        return Ok(());
+    }
+    tx.execute_batch(
+        "ALTER TABLE conversation_summaries RENAME TO conversation_summaries_legacy;
+         CREATE TABLE conversation_summaries (
+            conversation_id INTEGER NOT NULL,
+            context_level INTEGER NOT NULL,
+            summary TEXT NOT NULL,
+            last_message_id INTEGER NOT NULL,
+            updated_at TEXT NOT NULL,
+            PRIMARY KEY (conversation_id, context_level)
+         );",
+    )
+    .map_err(|e| format!("Failed to migrate summaries table: {}", e))?;
+    // Summaries without any history to belong to are dropped
+    if let Some(id) = legacy_id {
+        tx.execute(
+            "INSERT INTO conversation_summaries
+             SELECT ?1, context_level, summary, last_message_id, updated_at FROM conversation_summaries_legacy",
+            params![id],
+        )
+        .map_err(|e| format!("Failed to migrate summaries: {}", e))?;
+    }
+    tx.execute("DROP TABLE conversation_summaries_legacy", [])
+        .map_err(|e| format!("Failed to migrate summaries table: {}", e))?;
+    Ok(())
+}
+
+/// 4: Full-text index over message content, kept in sync by triggers and
+/// filled with the existing history
+fn add_search_index(tx: &Transaction) -> Result<(), String> {
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS chat_history_fts USING fts5(
+            content,
+            content = 'chat_history',
+            content_rowid = 'id',
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        CREATE TRIGGER IF NOT EXISTS chat_history_fts_insert AFTER INSERT ON chat_history BEGIN
+            INSERT INTO chat_history_fts (rowid, content) VALUES (new.id, new.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS chat_history_fts_delete AFTER DELETE ON chat_history BEGIN
+            INSERT INTO chat_history_fts (chat_history_fts, rowid, content) VALUES ('delete', old.id, old.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS chat_history_fts_update AFTER UPDATE OF content ON chat_history BEGIN
+            INSERT INTO chat_history_fts (chat_history_fts, rowid, content) VALUES ('delete', old.id, old.content);
+            INSERT INTO chat_history_fts (rowid, content) VALUES (new.id, new.content);
+        END;
+        INSERT INTO chat_history_fts (chat_history_fts) VALUES ('rebuild');",
+    )
+    .map_err(|e| format!("Failed to create search index: {}", e))?;
+    Ok(())
+}
+
+/// 5: Ledger of every paid call, kept apart from the history so clearing or
+/// deleting conversations doesn't reset spending limits. Starts with the
+/// usage already recorded on stored messages.
+fn add_usage_ledger(tx: &Transaction) -> Result<(), String> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS usage_ledger (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            purpose TEXT NOT NULL,
+            model TEXT NOT NULL,
+            prompt_tokens INTEGER NOT NULL,
+            completion_tokens INTEGER NOT NULL,
+            cost REAL,
+            latency_ms INTEGER
+        );
+        CREATE INDEX IF NOT EXISTS idx_usage_ledger_timestamp ON usage_ledger (timestamp);
+        INSERT INTO usage_ledger (timestamp, purpose, model, prompt_tokens, completion_tokens, cost, latency_ms)
+            SELECT timestamp,
+                   CASE WHEN parent_id IS NOT NULL THEN 'commentary' ELSE 'reply' END,
+                   COALESCE(model, 'unknown'),
+                   COALESCE(prompt_tokens, 0), COALESCE(completion_tokens, 0), cost, latency_ms
+            FROM chat_history
+            WHERE prompt_tokens IS NOT NULL OR completion_tokens IS NOT NULL OR cost IS NOT NULL
+            ORDER BY id;",
+    )
+    .map_err(|e| format!("Failed to create usage ledger: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    /// The schema as the last release before versioning left it
+    const PRE_VERSIONING_SCHEMA: &str = "
+        CREATE TABLE chat_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            role TEXT NOT NULL,
+            content TEXT NOT NULL,
+            context_level INTEGER DEFAULT 0,
+            model TEXT
+        );
+        CREATE TABLE conversation_summaries (
+            context_level INTEGER PRIMARY KEY,
+            summary TEXT NOT NULL,
+            last_message_id INTEGER NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+        INSERT INTO chat_history (timestamp, role, content, context_level, model) VALUES
+            ('2024-01-01T10:00:00Z', 'user', 'hello there', 0, NULL),
+            ('2024-01-02T10:00:00Z', 'assistant', 'general kenobi', 0, 'some/model');
+        INSERT INTO conversation_summaries VALUES (0, 'they greeted', 1, '2024-01-02T10:00:00Z');
+    ";
+
+    #[test]
+    fn fresh_database_reaches_latest_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        for table in [
+            "chat_history",
+            "message_attachments",
+            "conversations",
+            "conversation_summaries",
+            "chat_history_fts",
+            "usage_ledger",
+        ] {
+            assert_eq!(
+                count(
+                    &conn,
+                    &format!(
+                        "SELECT COUNT(*) FROM sqlite_master WHERE name = '{}'",
+                        table
+                    )
+                ),
+                1,
+                "{}",
+                table
+            );
+        }
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM conversations"), 0);
+
+        // Running again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn pre_versioning_database_keeps_rows_and_summaries() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(PRE_VERSIONING_SCHEMA).unwrap();
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        let (conversation_id, title, created_at, updated_at): (i64, String, String, String) = conn
+            .query_row(
+                "SELECT id, title, created_at, updated_at FROM conversations",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(title, "Chat");
+        assert_eq!(created_at, "2024-01-01T10:00:00Z");
+        assert_eq!(updated_at, "2024-01-02T10:00:00Z");
+
+        assert_eq!(
+            count(
+                &conn,
+                &format!(
+                    "SELECT COUNT(*) FROM chat_history WHERE conversation_id = {} AND model IS NOT NULL",
+                    conversation_id
+                )
+            ),
+            1
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM chat_history"), 2);
+        let summary: (i64, i64, String) = conn
+            .query_row(
+                "SELECT conversation_id, context_level, summary FROM conversation_summaries",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(summary, (conversation_id, 0, "they greeted".to_string()));
+
+        // Existing history is searchable, and new rows are indexed by the triggers
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM chat_history_fts WHERE chat_history_fts MATCH 'kenobi'"
+            ),
+            1
+        );
+        conn.execute(
+            "INSERT INTO chat_history (timestamp, role, content) VALUES ('t', 'user', 'kenobi again')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM chat_history_fts WHERE chat_history_fts MATCH 'kenobi'"
+            ),
+            2
+        );
+    }
+
+    #[test]
+    fn usage_ledger_starts_with_recorded_usage() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_migrations(&mut conn, &MIGRATIONS[..4]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO chat_history (timestamp, role, content, model, prompt_tokens, completion_tokens, cost, parent_id) VALUES
+                ('2024-03-01T10:00:00Z', 'user', 'hi', NULL, NULL, NULL, NULL, NULL),
+                ('2024-03-01T10:00:01Z', 'assistant', 'hello', 'a/model', 10, 5, 0.25, NULL),
+                ('2024-03-01T10:00:02Z', 'character', 'nice', 'b/model', 3, 2, NULL, 2),
+                ('2024-03-01T10:00:02Z', 'character', 'again', 'b/model', NULL, NULL, NULL, 2);",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+
+        let rows: Vec<(String, String, i64, Option<f64>)> = conn
+            .prepare("SELECT purpose, model, prompt_tokens + completion_tokens, cost FROM usage_ledger ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("reply".to_string(), "a/model".to_string(), 15, Some(0.25)),
+                ("commentary".to_string(), "b/model".to_string(), 5, None),
+            ]
+        );
+
+        // The ledger outlives the history it was filled from
+        conn.execute("DELETE FROM chat_history", []).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM usage_ledger"), 2);
+    }
+
+    #[test]
+    fn failed_migration_stops_at_last_good_version() {
+        fn broken(tx: &Transaction) -> Result<(), String> {
+            tx.execute("CREATE TABLE half_done (id INTEGER)", [])
+                .map_err(|e| e.to_string())?;
+            Err("boom".to_string())
+        }
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        let error = run_migrations(&mut conn, &[create_chat_history, broken]).unwrap_err();
+
+        assert!(error.contains("Migration 2 failed: boom"), "{}", error);
+        assert_eq!(user_version(&conn), 1);
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'half_done'"
+            ),
+            0
+        );
+
+        // The remaining migrations pick up from there
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+
+        let error = migrate(&mut conn).unwrap_err();
+        assert!(error.contains("newer version"), "{}", error);
+        assert_eq!(user_version(&conn), SCHEMA_VERSION + 1);
+    }
+
+    #[test]
+    fn upgrading_a_file_backs_it_up_first() {
+        let dir = std::env::temp_dir().join(format!("migrations_backup_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("chat_history.db");
+        let mut conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(PRE_VERSIONING_SCHEMA).unwrap();
+        migrate(&mut conn).unwrap();
+
+        let backups: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("chat_history.v0.") && name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1, "{:?}", backups);
+
+        let backup = Connection::open(dir.join(&backups[0])).unwrap();
+        assert_eq!(user_version(&backup), 0);
+        assert_eq!(count(&backup, "SELECT COUNT(*) FROM chat_history"), 2);
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -10,6 +10,208 @@ pub struct ChatMessage {
     pub role: String,
     pub content: String,
     pub context_level: u8,
+    /// Whether the reply was cancelled before it finished streaming
+    #[serde(default)]
+    pub cancelled: bool,
+    /// Id of the request that produced this message, shared by prompt and reply
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Model that produced a reply
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Tokens and cost reported by the provider for a reply
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Milliseconds from sending the request to the end of the reply
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    /// Why the model stopped, e.g. "stop", "length" or "end_turn"
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    /// Message this one responds to, e.g. the assistant reply a comment is about
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+    /// Reasoning the model produced before the reply; never sent back as context
+    #[serde(default)]
+    pub reasoning: Option<String>,
+    /// Models that failed before the one that produced the reply, in order
+    #[serde(default)]
+    pub fallback_hops: Vec<FallbackHop>,
+    /// Conversation the message belongs to
+    #[serde(default)]
+    pub conversation_id: Option<i64>,
+}
+
+/// A chat message about to be inserted into the database
+#[derive(Debug, Clone, Default)]
+pub struct NewChatMessage {
+    pub conversation_id: i64,
+    pub timestamp: String,
+    pub role: String,
+    pub content: String,
+    pub context_level: u8,
+    pub cancelled: bool,
+    pub request_id: Option<String>,
+    pub model: Option<String>,
+    pub usage: Option<Usage>,
+    pub latency_ms: Option<u64>,
+    pub finish_reason: Option<String>,
+    pub parent_id: Option<i64>,
+    pub reasoning: Option<String>,
+    pub fallback_hops: Vec<FallbackHop>,
+}
+
+impl NewChatMessage {
+    pub fn new(
+        conversation_id: i64,
+        timestamp: &str,
+        role: &str,
+        content: &str,
+        context_level: u8,
+    ) -> Self {
+        Self {
+            conversation_id,
+            timestamp: timestamp.to_string(),
+            role: role.to_string(),
+            content: content.to_string(),
+            context_level,
+            ..Default::default()
+        }
+    }
+}
+
+/// A model that failed and was replaced by the next one in the fallback list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FallbackHop {
+    pub model: String,
+    /// HTTP status and error body, or the network error
+    pub error: String,
+}
+
+/// Token counts and cost reported by the provider for one reply
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// Cost in USD, when the provider reports it (OpenRouter does)
+    pub cost: Option<f64>,
+}
+
+impl Usage {
+    /// Overwrites the fields `other` reports, for providers that send usage in
+    /// several partial events over one stream
+    pub fn merge(&mut self, other: &Usage) {
+        if other.prompt_tokens > 0 {
+            self.prompt_tokens = other.prompt_tokens;
+        }
+        if other.completion_tokens > 0 {
+            self.completion_tokens = other.completion_tokens;
+        }
+        if other.cost.is_some() {
+            self.cost = other.cost;
+        }
+    }
+
+    /// Adds the usage of another response, e.g. a later tool-call round
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        if let Some(cost) = other.cost {
+            self.cost = Some(self.cost.unwrap_or(0.0) + cost);
+        }
+    }
+}
+
+/// Token and cost totals for one model on one day
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReportRow {
+    /// Local calendar date (YYYY-MM-DD)
+    pub date: String,
+    pub model: String,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    /// Total cost in USD of the calls that reported one
+    pub cost: f64,
+    pub avg_latency_ms: Option<f64>,
+}
+
+/// A file attached to a stored chat message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageAttachment {
+    pub id: i64,
+    pub message_id: i64,
+    pub file_name: String,
+    /// Copy of the file in the attachments folder
+    pub stored_path: String,
+    pub mime_type: String,
+    /// "image", "text" or "pdf"
+    pub kind: String,
+    pub size_bytes: i64,
+    pub created_at: String,
+}
+
+/// Narrows a history query; unset fields don't filter
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryFilters {
+    pub conversation_id: Option<i64>,
+    pub role: Option<String>,
+    pub context_level: Option<u8>,
+    /// First local date to include (YYYY-MM-DD)
+    pub from: Option<String>,
+    /// Last local date to include (YYYY-MM-DD)
+    pub to: Option<String>,
+}
+
+/// A message matching a full-text search
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatSearchHit {
+    pub message_id: i64,
+    pub conversation_id: Option<i64>,
+    pub timestamp: String,
+    pub role: String,
+    pub context_level: u8,
+    /// Excerpt around the match as HTML: the message text is escaped and
+    /// matched terms are wrapped in `<mark>` tags
+    pub snippet: String,
+    /// The same excerpt as plain text, without highlighting
+    pub excerpt: String,
+    /// BM25 relevance, lower is better
+    pub rank: f64,
+}
+
+/// One page of chat history, oldest message first
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryPage {
+    pub messages: Vec<ChatMessage>,
+    /// Pass as `before_id` to get the page before this one; `None` once the
+    /// start of the history is reached
+    pub next_before_id: Option<i64>,
+}
+
+/// A chat thread with its own history and summaries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: i64,
+    pub title: String,
+    /// Notes added to the system prompt for this conversation only
+    pub persona: Option<String>,
+    pub created_at: String,
+    /// Time of the latest message, or of creation
+    pub updated_at: String,
+    pub archived: bool,
+}
+
+/// Rolling summary of the history that no longer fits a context level's window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub conversation_id: i64,
+    pub context_level: u8,
+    pub summary: String,
+    /// Id of the newest message folded into the summary
+    pub last_message_id: i64,
+    pub updated_at: String,
 }
 
 /// Response from the chat API including optional character comments
@@ -17,4 +219,5 @@ pub struct ChatMessage {
 pub struct ChatResponse {
     pub main_response: String,
     pub character_comments: Option<Vec<String>>,
+    pub request_id: String,
 }
@@ -36,6 +36,11 @@ pub fn get_screenshots_dir() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join("History").join("Screenshots"))
 }
 
+/// Gets the chat attachments directory path
+pub fn get_attachments_dir() -> Result<PathBuf, String> {
+    get_app_data_dir().map(|p| p.join("History").join("Attachments"))
+}
+
 /// Gets the database file path
 pub fn get_db_path() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join("chat_history.db"))
@@ -51,6 +56,11 @@ pub fn get_llm_config_path() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join(".llm_config.json"))
 }
 
+/// Gets the file holding the id of the conversation the chat is in
+pub fn get_active_conversation_path() -> Result<PathBuf, String> {
+    get_app_data_dir().map(|p| p.join(".active_conversation"))
+}
+
 /// Gets the system prompt file path
 pub fn get_system_prompt_path() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join(".system_prompt"))
@@ -66,6 +76,11 @@ pub fn get_dialogue_prompt_path() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join(".dialogue_prompt"))
 }
 
+/// Gets the directory of named prompt snippets
+pub fn get_prompt_snippets_dir() -> Result<PathBuf, String> {
+    get_app_data_dir().map(|p| p.join("prompt_snippets"))
+}
+
 /// Gets the hitbox configuration file path
 pub fn get_hitbox_path() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join(".hitbox.json"))
@@ -76,6 +91,19 @@ pub fn get_model_config_path() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join(".model_config.json"))
 }
 
+/// Gets the emotion mapping file path for a Live2D model folder
+pub fn get_emotion_map_path(model_folder: &str) -> Result<PathBuf, String> {
+    get_app_data_dir().map(|p| {
+        p.join("emotion_maps")
+            .join(format!("{}.json", model_folder))
+    })
+}
+
+/// Gets the cached OpenRouter model catalog path
+pub fn get_model_catalog_path() -> Result<PathBuf, String> {
+    get_app_data_dir().map(|p| p.join("model_catalog.json"))
+}
+
 /// Gets the overlay scale file path
 pub fn get_overlay_scale_path() -> Result<PathBuf, String> {
     get_app_data_dir().map(|p| p.join(".overlay_scale"))
@@ -0,0 +1,171 @@
+//! Retry policy for LLM requests
+//!
+//! Transient failures (rate limits, overloaded or unreachable upstreams) are
+//! retried with exponential backoff and full jitter, waiting at least as long
+//! as the provider's `Retry-After` header asks.
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How often and how patiently a request to one model is retried
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts per model, including the first
+    pub max_attempts: u32,
+    /// Upper bound of the first backoff, doubled on each retry
+    pub base_delay_ms: u64,
+    /// Longest wait between attempts, including a `Retry-After` wait
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 20_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `retry` (starting at 1). A `Retry-After`
+    /// wait is honoured as the minimum, capped at `max_delay_ms`.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        let max = Duration::from_millis(self.max_delay_ms);
+        let ceiling = self
+            .base_delay_ms
+            .saturating_mul(1u64 << retry.saturating_sub(1).min(16))
+            .min(self.max_delay_ms);
+        let backoff = Duration::from_millis(jitter(ceiling));
+        backoff.max(retry_after.unwrap_or_default()).min(max)
+    }
+}
+
+/// Whether a response status is worth retrying or falling back from
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+/// Whether a send error is a transient network failure (connection refused
+/// or reset, timeout) rather than a problem with the request itself
+pub fn is_retryable_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout() || error.is_request()
+}
+
+/// Reads a `Retry-After` header given either in seconds or as an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        // Rejects negative, NaN and overflowing values such as "inf" or "1e30"
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// Uniformly random value in `0..=max` ("full jitter")
+fn jitter(max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    // RandomState is seeded per instance, which is random enough for spreading retries
+    let random = RandomState::new().build_hasher().finish();
+    random % (max + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers_with_retry_after(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn reads_retry_after_seconds() {
+        assert_eq!(
+            retry_after(&headers_with_retry_after("3")),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            retry_after(&headers_with_retry_after(" 1.5 ")),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn rejects_unrepresentable_retry_after() {
+        for value in ["-1", "inf", "infinity", "NaN", "1e30"] {
+            assert_eq!(
+                retry_after(&headers_with_retry_after(value)),
+                None,
+                "{}",
+                value
+            );
+        }
+        assert_eq!(retry_after(&headers_with_retry_after("soon")), None);
+    }
+
+    #[test]
+    fn reads_retry_after_http_date() {
+        let at = chrono::Utc::now() + chrono::Duration::seconds(30);
+        let wait = retry_after(&headers_with_retry_after(&at.to_rfc2822())).unwrap();
+        assert!(wait > Duration::from_secs(25) && wait <= Duration::from_secs(30));
+
+        let past = chrono::Utc::now() - chrono::Duration::seconds(30);
+        assert_eq!(
+            retry_after(&headers_with_retry_after(&past.to_rfc2822())),
+            None
+        );
+    }
+
+    #[test]
+    fn delay_stays_within_doubling_ceiling() {
+        let policy = RetryPolicy::default();
+        for retry in 1..=4 {
+            let ceiling = Duration::from_millis(policy.base_delay_ms << (retry - 1));
+            for _ in 0..20 {
+                assert!(policy.delay(retry, None) <= ceiling);
+            }
+        }
+    }
+
+    #[test]
+    fn delay_is_capped_for_late_retries() {
+        let policy = RetryPolicy::default();
+        let max = Duration::from_millis(policy.max_delay_ms);
+        for retry in [10, 64, u32::MAX] {
+            assert!(policy.delay(retry, None) <= max);
+        }
+    }
+
+    #[test]
+    fn delay_honours_retry_after_up_to_the_cap() {
+        let policy = RetryPolicy::default();
+        assert!(policy.delay(1, Some(Duration::from_secs(5))) >= Duration::from_secs(5));
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(3600))),
+            Duration::from_millis(policy.max_delay_ms)
+        );
+    }
+
+    #[test]
+    fn delay_without_backoff_is_zero() {
+        let policy = RetryPolicy {
+            base_delay_ms: 0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.delay(3, None), Duration::ZERO);
+    }
+}
@@ -0,0 +1,342 @@
+//! Server-sent events decoding for streamed chat completions
+//!
+//! `SseDecoder` turns raw response bytes into SSE events, buffering partial
+//! lines (and the UTF-8 sequences inside them) across chunks. `interpret`
+//! then classifies each event for the chat stream: a JSON payload, the end
+//! of the stream, or an error reported mid-stream.
+
+use serde_json::Value;
+
+/// One dispatched server-sent event
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    /// Value of the `event:` field, if the server named the event
+    pub event: Option<String>,
+    /// `data:` lines joined with newlines
+    pub data: String,
+}
+
+/// Incremental SSE parser fed with response body chunks
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Option<String>,
+}
+
+impl SseDecoder {
+    /// Feeds a chunk of bytes, returning the events it completed
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        let mut consumed = 0;
+        while let Some(offset) = self.buffer[consumed..].iter().position(|b| *b == b'\n') {
+            let end = consumed + offset;
+            // Lines are only decoded once complete, so multi-byte characters
+            // split across chunks are never cut in half
+            let line = String::from_utf8_lossy(&self.buffer[consumed..end]).into_owned();
+            consumed = end + 1;
+            events.extend(self.process_line(line.strip_suffix('\r').unwrap_or(&line)));
+        }
+        self.buffer.drain(..consumed);
+
+        events
+    }
+
+    /// Flushes what is left once the body ends, including a final event
+    /// that was not followed by a blank line
+    pub fn finish(&mut self) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        if !self.buffer.is_empty() {
+            let rest = std::mem::take(&mut self.buffer);
+            let line = String::from_utf8_lossy(&rest).into_owned();
+            events.extend(self.process_line(line.strip_suffix('\r').unwrap_or(&line)));
+        }
+        events.extend(self.process_line(""));
+        events
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        // A blank line dispatches the event collected so far
+        if line.is_empty() {
+            let event = self.event.take();
+            return self.data.take().map(|data| SseEvent { event, data });
+        }
+
+        // Comments, e.g. OpenRouter's ": OPENROUTER PROCESSING" keep-alives
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "data" => match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            },
+            "event" => self.event = Some(value.to_string()),
+            // id and retry only matter for reconnecting, which chat streams don't do
+            _ => {}
+        }
+        None
+    }
+}
+
+/// What an SSE event means for a chat stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatStreamEvent {
+    /// A provider payload to hand to `LlmProvider::parse_stream_event`
+    Data(Value),
+    /// The provider signalled the end of the stream
+    Done,
+    /// The provider reported an error after the stream had started
+    Error(String),
+}
+
+/// Classifies an SSE event; returns `None` for events that carry nothing usable
+pub fn interpret(event: &SseEvent) -> Option<ChatStreamEvent> {
+    let data = event.data.trim();
+    if data == "[DONE]" {
+        return Some(ChatStreamEvent::Done);
+    }
+
+    let json: Value = serde_json::from_str(data).ok()?;
+
+    // OpenRouter sends {"error": {...}}, Anthropic an `error` event with
+    // {"type": "error", "error": {...}}
+    let error = &json["error"];
+    if !error.is_null() || event.event.as_deref() == Some("error") {
+        let message = error["message"]
+            .as_str()
+            .or_else(|| error.as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| json.to_string());
+        return Some(ChatStreamEvent::Error(message));
+    }
+
+    if json["type"] == "message_stop" {
+        return Some(ChatStreamEvent::Done);
+    }
+
+    Some(ChatStreamEvent::Data(json))
+}
+
+/// Why a model stopped generating, normalized across providers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// Natural end of the reply or a stop sequence
+    Stop,
+    /// The max_tokens limit cut the reply off
+    Length,
+    /// The model stopped to call tools
+    ToolCalls,
+    /// The provider withheld or cut the reply for policy reasons
+    ContentFilter,
+    /// The provider failed mid-generation
+    Error,
+    /// The stream ended without the provider saying why
+    Incomplete,
+    /// A reason this app doesn't know, kept verbatim
+    Other(String),
+}
+
+impl FinishReason {
+    /// Maps a provider's finish/stop reason string
+    pub fn parse(reason: &str) -> Self {
+        match reason {
+            "stop" | "end_turn" | "stop_sequence" | "eos" => FinishReason::Stop,
+            "length" | "max_tokens" => FinishReason::Length,
+            "tool_calls" | "tool_use" | "function_call" => FinishReason::ToolCalls,
+            "content_filter" | "refusal" => FinishReason::ContentFilter,
+            "error" => FinishReason::Error,
+            other => FinishReason::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::ToolCalls => "tool_calls",
+            FinishReason::ContentFilter => "content_filter",
+            FinishReason::Error => "error",
+            FinishReason::Incomplete => "incomplete",
+            FinishReason::Other(reason) => reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{LlmProvider, ProviderKind, StreamDelta};
+
+    const OPENROUTER_STREAM: &str = include_str!("../tests/fixtures/openrouter_stream.sse");
+    const OPENAI_CRLF_STREAM: &str = include_str!("../tests/fixtures/openai_crlf_stream.sse");
+    const ANTHROPIC_STREAM: &str = include_str!("../tests/fixtures/anthropic_stream.sse");
+    const OPENROUTER_ERROR_STREAM: &str =
+        include_str!("../tests/fixtures/openrouter_error_stream.sse");
+    const MULTILINE_DATA_STREAM: &str = include_str!("../tests/fixtures/multiline_data_stream.sse");
+
+    /// Decodes a recorded stream fed in chunks of `chunk_size` bytes
+    fn decode(fixture: &str, chunk_size: usize) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::default();
+        let mut events = Vec::new();
+        for chunk in fixture.as_bytes().chunks(chunk_size) {
+            events.extend(decoder.push(chunk));
+        }
+        events.extend(decoder.finish());
+        events
+    }
+
+    /// Runs a recorded stream through the decoder and provider parser up to
+    /// the first Done or Error, returning the text, finish reason and ending
+    fn replay(
+        fixture: &str,
+        kind: ProviderKind,
+        chunk_size: usize,
+    ) -> (String, Option<FinishReason>, Option<ChatStreamEvent>) {
+        let provider = LlmProvider {
+            kind,
+            base_url: String::new(),
+            api_key: None,
+            routing: Default::default(),
+        };
+        let mut text = String::new();
+        let mut finish_reason = None;
+
+        for event in decode(fixture, chunk_size) {
+            match interpret(&event) {
+                Some(ChatStreamEvent::Data(json)) => {
+                    for delta in provider.parse_stream_event(&json) {
+                        match delta {
+                            StreamDelta::Text(chunk) => text.push_str(&chunk),
+                            StreamDelta::Finish(reason) => finish_reason = Some(reason),
+                            _ => {}
+                        }
+                    }
+                }
+                Some(end) => return (text, finish_reason, Some(end)),
+                None => {}
+            }
+        }
+        (text, finish_reason, None)
+    }
+
+    #[test]
+    fn decodes_openrouter_stream_with_keep_alives() {
+        let (text, finish_reason, end) = replay(OPENROUTER_STREAM, ProviderKind::OpenRouter, 4096);
+        assert_eq!(text, "Héllo there 👋 — how can I help?");
+        assert_eq!(finish_reason, Some(FinishReason::Stop));
+        assert_eq!(end, Some(ChatStreamEvent::Done));
+    }
+
+    #[test]
+    fn chunk_boundaries_do_not_change_the_result() {
+        let whole = decode(OPENROUTER_STREAM, OPENROUTER_STREAM.len());
+        // Single bytes split every multi-byte character across chunks
+        for chunk_size in [1, 2, 3, 7, 64] {
+            assert_eq!(decode(OPENROUTER_STREAM, chunk_size), whole);
+        }
+    }
+
+    #[test]
+    fn handles_crlf_line_endings() {
+        let (text, finish_reason, end) = replay(OPENAI_CRLF_STREAM, ProviderKind::OpenAI, 3);
+        assert_eq!(text, "Line one\nLine two");
+        assert_eq!(finish_reason, Some(FinishReason::Length));
+        assert_eq!(end, Some(ChatStreamEvent::Done));
+    }
+
+    #[test]
+    fn decodes_named_anthropic_events() {
+        let events = decode(ANTHROPIC_STREAM, 11);
+        assert_eq!(events[0].event.as_deref(), Some("message_start"));
+        assert!(events.iter().all(|e| e.event.is_some()));
+
+        let (text, finish_reason, end) = replay(ANTHROPIC_STREAM, ProviderKind::Anthropic, 11);
+        assert_eq!(text, "Hi! Ready when you are.");
+        assert_eq!(finish_reason, Some(FinishReason::Stop));
+        assert_eq!(end, Some(ChatStreamEvent::Done));
+    }
+
+    #[test]
+    fn reports_mid_stream_errors() {
+        let (text, _, end) = replay(OPENROUTER_ERROR_STREAM, ProviderKind::OpenRouter, 16);
+        assert_eq!(text, "Partial ");
+        assert_eq!(
+            end,
+            Some(ChatStreamEvent::Error(
+                "Provider returned error: upstream timeout".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn joins_multi_line_data_fields() {
+        let events = decode(MULTILINE_DATA_STREAM, 8);
+        assert_eq!(events.len(), 2);
+        assert!(events[0].data.contains('\n'));
+
+        // The final event has no trailing blank line and is flushed by finish()
+        let (text, finish_reason, end) =
+            replay(MULTILINE_DATA_STREAM, ProviderKind::OpenAICompatible, 8);
+        assert_eq!(text, "multi-line payload");
+        assert_eq!(finish_reason, Some(FinishReason::Stop));
+        assert_eq!(end, None);
+    }
+
+    #[test]
+    fn reports_the_serving_model() {
+        for (fixture, kind, model) in [
+            (
+                OPENROUTER_STREAM,
+                ProviderKind::OpenRouter,
+                "openai/gpt-4o-mini",
+            ),
+            (
+                ANTHROPIC_STREAM,
+                ProviderKind::Anthropic,
+                "claude-3-5-haiku-latest",
+            ),
+        ] {
+            let provider = LlmProvider {
+                kind,
+                base_url: String::new(),
+                api_key: None,
+                routing: Default::default(),
+            };
+            let first = decode(fixture, 64)
+                .iter()
+                .filter_map(|event| match interpret(event) {
+                    Some(ChatStreamEvent::Data(json)) => Some(provider.parse_stream_event(&json)),
+                    _ => None,
+                })
+                .flatten()
+                .find_map(|delta| match delta {
+                    StreamDelta::Model(model) => Some(model),
+                    _ => None,
+                });
+            assert_eq!(first.as_deref(), Some(model));
+        }
+    }
+
+    #[test]
+    fn parses_provider_finish_reasons() {
+        assert_eq!(FinishReason::parse("end_turn"), FinishReason::Stop);
+        assert_eq!(FinishReason::parse("max_tokens"), FinishReason::Length);
+        assert_eq!(FinishReason::parse("tool_use"), FinishReason::ToolCalls);
+        assert_eq!(
+            FinishReason::parse("pause_turn"),
+            FinishReason::Other("pause_turn".to_string())
+        );
+        assert_eq!(FinishReason::parse("pause_turn").as_str(), "pause_turn");
+    }
+}
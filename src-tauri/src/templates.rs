@@ -0,0 +1,236 @@
+//! Runtime templating for the system, character and dialogue prompts
+//!
+//! Supports `{{variable}}` substitution, `{{#if variable}}…{{else}}…{{/if}}`
+//! conditionals and `{{> snippet}}` includes of named snippets. Unknown
+//! variables and snippets are left as written so mistakes show up in the
+//! prompt preview instead of silently disappearing.
+
+use std::collections::HashMap;
+
+/// How deep snippets may include other snippets
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Values available to a template while it renders
+#[derive(Debug, Clone, Default)]
+pub struct TemplateContext {
+    pub variables: HashMap<String, String>,
+    /// Snippet bodies by name, themselves rendered as templates
+    pub snippets: HashMap<String, String>,
+}
+
+/// Renders a prompt template
+pub fn render(template: &str, context: &TemplateContext) -> String {
+    render_at_depth(template, context, 0)
+}
+
+fn render_at_depth(template: &str, context: &TemplateContext, depth: usize) -> String {
+    let tokens = tokenize(template);
+    let mut pos = 0;
+    let nodes = parse_block(&tokens, &mut pos, false);
+    let mut out = String::with_capacity(template.len());
+    render_nodes(&nodes, context, depth, &mut out);
+    out
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    /// A `{{…}}` tag: the raw text and its trimmed contents
+    Tag(&'a str, &'a str),
+}
+
+enum Node<'a> {
+    Text(&'a str),
+    Variable {
+        raw: &'a str,
+        name: &'a str,
+    },
+    Include {
+        raw: &'a str,
+        name: &'a str,
+    },
+    If {
+        name: &'a str,
+        then: Vec<Node<'a>>,
+        otherwise: Vec<Node<'a>>,
+    },
+}
+
+fn tokenize(template: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+
+    while let Some(open) = rest.find("{{") {
+        let Some(close) = rest[open..].find("}}").map(|c| open + c) else {
+            break;
+        };
+        if open > 0 {
+            tokens.push(Token::Text(&rest[..open]));
+        }
+        tokens.push(Token::Tag(
+            &rest[open..close + 2],
+            rest[open + 2..close].trim(),
+        ));
+        rest = &rest[close + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+
+    tokens
+}
+
+/// Parses tokens until the end or, inside a conditional, until its `{{else}}`
+/// or `{{/if}}`, which is left for the caller to consume
+fn parse_block<'a>(tokens: &[Token<'a>], pos: &mut usize, in_if: bool) -> Vec<Node<'a>> {
+    let mut nodes = Vec::new();
+
+    while let Some(token) = tokens.get(*pos) {
+        match *token {
+            Token::Text(text) => nodes.push(Node::Text(text)),
+            Token::Tag(_, "else") | Token::Tag(_, "/if") if in_if => return nodes,
+            Token::Tag(raw, inner) => {
+                if let Some(name) = inner.strip_prefix("#if ") {
+                    *pos += 1;
+                    let then = parse_block(tokens, pos, true);
+                    let mut otherwise = Vec::new();
+                    if matches!(tokens.get(*pos), Some(Token::Tag(_, "else"))) {
+                        *pos += 1;
+                        otherwise = parse_block(tokens, pos, true);
+                    }
+                    // An unclosed conditional runs to the end of the template
+                    nodes.push(Node::If {
+                        name: name.trim(),
+                        then,
+                        otherwise,
+                    });
+                } else if let Some(name) = inner.strip_prefix('>') {
+                    nodes.push(Node::Include {
+                        raw,
+                        name: name.trim(),
+                    });
+                } else {
+                    nodes.push(Node::Variable { raw, name: inner });
+                }
+            }
+        }
+        *pos += 1;
+    }
+
+    nodes
+}
+
+fn render_nodes(nodes: &[Node], context: &TemplateContext, depth: usize, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Variable { raw, name } => match context.variables.get(*name) {
+                Some(value) => out.push_str(value),
+                None => out.push_str(raw),
+            },
+            Node::Include { raw, name } => match context.snippets.get(*name) {
+                Some(_) if depth >= MAX_INCLUDE_DEPTH => {}
+                Some(snippet) => out.push_str(&render_at_depth(snippet, context, depth + 1)),
+                None => out.push_str(raw),
+            },
+            Node::If {
+                name,
+                then,
+                otherwise,
+            } => {
+                let truthy = context
+                    .variables
+                    .get(*name)
+                    .is_some_and(|value| !value.trim().is_empty());
+                render_nodes(if truthy { then } else { otherwise }, context, depth, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(variables: &[(&str, &str)], snippets: &[(&str, &str)]) -> TemplateContext {
+        let to_map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        TemplateContext {
+            variables: to_map(variables),
+            snippets: to_map(snippets),
+        }
+    }
+
+    #[test]
+    fn substitutes_variables_and_keeps_unknown_ones() {
+        let ctx = context(&[("name", "Mika")], &[]);
+        assert_eq!(
+            render("Hi {{ name }}, {{mood}}!", &ctx),
+            "Hi Mika, {{mood}}!"
+        );
+        assert_eq!(render("{{name", &ctx), "{{name");
+    }
+
+    #[test]
+    fn renders_conditionals() {
+        let template = "{{#if screen}}Looking{{else}}Blind{{/if}}.";
+        assert_eq!(
+            render(template, &context(&[("screen", "yes")], &[])),
+            "Looking."
+        );
+        assert_eq!(
+            render(template, &context(&[("screen", "  ")], &[])),
+            "Blind."
+        );
+        assert_eq!(render(template, &context(&[], &[])), "Blind.");
+    }
+
+    #[test]
+    fn renders_nested_conditionals() {
+        let template = "{{#if a}}A{{#if b}}B{{else}}-{{/if}}{{else}}none{{/if}}!";
+        assert_eq!(
+            render(template, &context(&[("a", "1"), ("b", "1")], &[])),
+            "AB!"
+        );
+        assert_eq!(render(template, &context(&[("a", "1")], &[])), "A-!");
+        assert_eq!(render(template, &context(&[("b", "1")], &[])), "none!");
+    }
+
+    #[test]
+    fn unclosed_conditional_runs_to_the_end() {
+        let ctx = context(&[("a", "1")], &[]);
+        assert_eq!(render("x{{#if a}}y{{else}}z", &ctx), "xy");
+        assert_eq!(render("x{{#if b}}y{{else}}z", &ctx), "xz");
+        assert_eq!(render("x{{#if b}}y", &ctx), "x");
+    }
+
+    #[test]
+    fn stray_closing_tags_are_left_as_written() {
+        let ctx = context(&[], &[]);
+        assert_eq!(render("a{{/if}}b{{else}}c", &ctx), "a{{/if}}b{{else}}c");
+    }
+
+    #[test]
+    fn includes_snippets_with_their_own_tags() {
+        let ctx = context(
+            &[("name", "Mika")],
+            &[("greeting", "Hello {{name}}{{#if name}}!{{/if}}")],
+        );
+        assert_eq!(
+            render("{{> greeting}} {{>missing}}", &ctx),
+            "Hello Mika! {{>missing}}"
+        );
+    }
+
+    #[test]
+    fn recursive_includes_stop_at_the_depth_limit() {
+        let ctx = context(&[], &[("loop", "x{{> loop}}")]);
+        assert_eq!(render("{{> loop}}", &ctx), "x".repeat(MAX_INCLUDE_DEPTH));
+
+        let ctx = context(&[], &[("a", "a{{> b}}"), ("b", "b{{> a}}")]);
+        assert_eq!(render("{{> a}}", &ctx).len(), MAX_INCLUDE_DEPTH);
+    }
+}
@@ -0,0 +1,237 @@
+//! Tool registry for model function calling
+//!
+//! Tools are advertised to the model with a JSON schema and run on the Rust
+//! side when the model asks for them. Handlers return a plain-text result
+//! that is sent back to the model verbatim.
+
+use crate::db::{search_chat_history_internal, Db};
+use crate::llm::ToolDefinition;
+use crate::models::HistoryFilters;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+
+/// Boxed future returned by a tool handler
+pub type ToolFuture = Pin<Box<dyn Future<Output = Result<String, String>> + Send>>;
+
+/// Async handler that receives the database and the parsed arguments object
+pub type ToolHandler = Arc<dyn Fn(Db, Value) -> ToolFuture + Send + Sync>;
+
+/// A tool the model can call
+pub struct Tool {
+    pub definition: ToolDefinition,
+    handler: ToolHandler,
+}
+
+/// Collection of tools available to the assistant
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+impl ToolRegistry {
+    /// Registers a tool under `name`, replacing any tool with the same name
+    pub fn register<F, Fut>(&mut self, name: &str, description: &str, parameters: Value, handler: F)
+    where
+        F: Fn(Db, Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        self.tools.retain(|t| t.definition.name != name);
+        self.tools.push(Tool {
+            definition: ToolDefinition {
+                name: name.to_string(),
+                description: description.to_string(),
+                parameters,
+            },
+            handler: Arc::new(move |db, args| Box::pin(handler(db, args))),
+        });
+    }
+
+    /// Definitions of every registered tool, for advertising in requests
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.iter().map(|t| t.definition.clone()).collect()
+    }
+
+    /// Runs a tool with the raw JSON arguments string produced by the model
+    pub async fn invoke(&self, db: &Db, name: &str, arguments: &str) -> Result<String, String> {
+        let tool = self
+            .tools
+            .iter()
+            .find(|t| t.definition.name == name)
+            .ok_or_else(|| format!("Unknown tool: {}", name))?;
+
+        let args: Value = if arguments.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(arguments)
+                .map_err(|e| format!("Invalid arguments for {}: {}", name, e))?
+        };
+
+        (tool.handler)(db.clone(), args).await
+    }
+}
+
+/// Gets the registry of built-in tools
+pub fn tool_registry() -> &'static ToolRegistry {
+    static REGISTRY: OnceLock<ToolRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(builtin_tools)
+}
+
+fn builtin_tools() -> ToolRegistry {
+    let mut registry = ToolRegistry::default();
+
+    registry.register(
+        "get_current_time",
+        "Get the user's current local date, time, weekday and UTC offset.",
+        json!({ "type": "object", "properties": {} }),
+        |_, _| async {
+            let now = chrono::Local::now();
+            Ok(json!({
+                "datetime": now.to_rfc3339(),
+                "weekday": now.format("%A").to_string(),
+                "utc_offset": now.format("%:z").to_string()
+            })
+            .to_string())
+        },
+    );
+
+    registry.register(
+        "get_system_info",
+        "Get the user's operating system, CPU architecture and the app version.",
+        json!({ "type": "object", "properties": {} }),
+        |_, _| async {
+            Ok(json!({
+                "os": std::env::consts::OS,
+                "arch": std::env::consts::ARCH,
+                "app_version": env!("CARGO_PKG_VERSION")
+            })
+            .to_string())
+        },
+    );
+
+    registry.register(
+        "search_chat_history",
+        "Search earlier messages in all conversations for words (case-insensitive). Returns excerpts of the best matches.",
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string", "description": "Text to look for" },
+                "limit": { "type": "integer", "description": "Maximum matches to return (default 5)" }
+            },
+            "required": ["query"]
+        }),
+        |db, args| async move {
+            let query = args["query"]
+                .as_str()
+                .filter(|q| !q.trim().is_empty())
+                .ok_or_else(|| "Missing query".to_string())?
+                .to_string();
+            let limit = args["limit"].as_i64().unwrap_or(5).clamp(1, 20);
+
+            let hits = db
+                .run(move |db| {
+                    search_chat_history_internal(db, &query, &HistoryFilters::default(), limit)
+                })
+                .await?;
+            let matches: Vec<Value> = hits
+                .iter()
+                .map(|hit| {
+                    json!({
+                        "timestamp": hit.timestamp,
+                        "role": hit.role,
+                        "excerpt": hit.excerpt
+                    })
+                })
+                .collect();
+
+            Ok(json!({ "matches": matches }).to_string())
+        },
+    );
+
+    registry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{create_conversation_internal, store_chat_message};
+    use crate::models::NewChatMessage;
+    use tauri::async_runtime::block_on;
+
+    fn echo_registry() -> ToolRegistry {
+        let mut registry = ToolRegistry::default();
+        registry.register("echo", "Old", json!({}), |_, _| async {
+            Ok("old".to_string())
+        });
+        registry.register("echo", "Echo", json!({}), |_, args| async move {
+            Ok(args.to_string())
+        });
+        registry
+    }
+
+    #[test]
+    fn registering_a_name_again_replaces_the_tool() {
+        let registry = echo_registry();
+        let definitions = registry.definitions();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].description, "Echo");
+
+        let result = block_on(registry.invoke(&Db::default(), "echo", r#"{"a":1}"#));
+        assert_eq!(result.unwrap(), r#"{"a":1}"#);
+    }
+
+    #[test]
+    fn invoke_checks_the_tool_and_its_arguments() {
+        let registry = echo_registry();
+        let db = Db::default();
+
+        let error = block_on(registry.invoke(&db, "missing", "{}")).unwrap_err();
+        assert_eq!(error, "Unknown tool: missing");
+
+        // Models send an empty string for tools without parameters
+        assert_eq!(block_on(registry.invoke(&db, "echo", "  ")).unwrap(), "{}");
+
+        let error = block_on(registry.invoke(&db, "echo", "{not json")).unwrap_err();
+        assert!(
+            error.starts_with("Invalid arguments for echo:"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn history_search_needs_a_query_and_caps_the_limit() {
+        let dir = std::env::temp_dir().join(format!("tools_search_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Db::at(dir.join("chat_history.db"));
+        let conversation =
+            create_conversation_internal(&db, "t", None, "2024-01-01T00:00:00Z").unwrap();
+        for i in 0..25 {
+            let content = format!("needle number {}", i);
+            let message =
+                NewChatMessage::new(conversation.id, "2024-01-01T00:00:00Z", "user", &content, 0);
+            store_chat_message(&db, &message).unwrap();
+        }
+
+        let search = |arguments: Value| {
+            block_on(tool_registry().invoke(&db, "search_chat_history", &arguments.to_string()))
+        };
+        let matches = |arguments: Value| {
+            let result: Value = serde_json::from_str(&search(arguments).unwrap()).unwrap();
+            result["matches"].as_array().unwrap().len()
+        };
+
+        assert_eq!(
+            search(json!({ "query": "  " })).unwrap_err(),
+            "Missing query"
+        );
+        assert_eq!(search(json!({})).unwrap_err(), "Missing query");
+        assert_eq!(matches(json!({ "query": "needle" })), 5);
+        assert_eq!(matches(json!({ "query": "needle", "limit": 100 })), 20);
+        assert_eq!(matches(json!({ "query": "needle", "limit": 0 })), 1);
+
+        db.close();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}